@@ -1,13 +1,14 @@
 ﻿use std::io::prelude::*;
 use std::fs::File;
 use std::path;
-use std::ptr::write;
-use std::io;
-use memory::Memory;
+use std::cmp;
+use memory::{Memory, Model};
+use inflate::{crc32, inflate};
+use state::{StateReader, StateWriter};
 
 // https://realboyemulator.files.wordpress.com/2013/01/gbcpuman.pdf Page 11
-#[derive(Debug)]
-enum CartridgeType {
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CartridgeType {
     RomOnly                 = 0x00,
     RomMBC1                 = 0x01,
     RomMBC1Ram              = 0x02,
@@ -30,40 +31,462 @@ enum CartridgeType {
     RomMBC5Rumble           = 0x1c,
     RomMBC5RumbleSRam       = 0x1d,
     RomMBC5RumbleSRamBatt   = 0x1e,
-    PocketCamera            = 0x1f,
+    PocketCamera            = 0xfc,
     BundaiTamas             = 0xfd,
     HudsonHUC3              = 0xfe,
     HudsonHUC1              = 0xff,
 }
 
+impl CartridgeType {
+    pub fn from_byte(value: u8) -> Option<CartridgeType> {
+        use self::CartridgeType::*;
+        match value {
+            0x00 => Some(RomOnly),
+            0x01 => Some(RomMBC1),
+            0x02 => Some(RomMBC1Ram),
+            0x03 => Some(RomMBC1RamBatt),
+            0x05 => Some(RomMBC2),
+            0x06 => Some(RomMBC2Batt),
+            0x08 => Some(RomRam),
+            0x09 => Some(RomRamBatt),
+            0x0b => Some(RomMMM01),
+            0x0c => Some(RomMMM01SRam),
+            0x0d => Some(RomMMM01SRamBatt),
+            0x0f => Some(RomMBC3TimerBatt),
+            0x10 => Some(RomMBC3TimerRamBatt),
+            0x11 => Some(RomMBC3),
+            0x12 => Some(RomMBC3Ram),
+            0x13 => Some(RomMBC3RamBatt),
+            0x19 => Some(RomMBC5),
+            0x1a => Some(RomMBC5Ram),
+            0x1b => Some(RomMBC5RamBatt),
+            0x1c => Some(RomMBC5Rumble),
+            0x1d => Some(RomMBC5RumbleSRam),
+            0x1e => Some(RomMBC5RumbleSRamBatt),
+            0xfc => Some(PocketCamera),
+            0xfd => Some(BundaiTamas),
+            0xfe => Some(HudsonHUC3),
+            0xff => Some(HudsonHUC1),
+            _ => None,
+        }
+    }
+
+    pub fn has_battery(&self) -> bool {
+        use self::CartridgeType::*;
+        matches!(*self,
+                 RomMBC1RamBatt | RomMBC2Batt | RomRamBatt | RomMMM01SRamBatt |
+                 RomMBC3TimerBatt | RomMBC3TimerRamBatt | RomMBC3RamBatt |
+                 RomMBC5RamBatt | RomMBC5RumbleSRamBatt | HudsonHUC3 | HudsonHUC1)
+    }
+
+    pub fn has_rtc(&self) -> bool {
+        use self::CartridgeType::*;
+        matches!(*self, RomMBC3TimerBatt | RomMBC3TimerRamBatt | HudsonHUC3)
+    }
+
+    pub fn has_rumble(&self) -> bool {
+        use self::CartridgeType::*;
+        matches!(*self, RomMBC5Rumble | RomMBC5RumbleSRam | RomMBC5RumbleSRamBatt)
+    }
+}
+
 const ROM_TYPE_OFFSET: u16 = 0x147;
 const ROM_SIZE_OFFSET: u16 = 0x148;
 const ROM_NAME_OFFSET: u16 = 0x134;
 const ROM_RAM_OFFSET:  u16 = 0x149;
+const ROM_CGB_OFFSET:  u16 = 0x143; // Bit 7 set by CGB enhanced and CGB only games
 
 #[derive(Debug)]
 pub enum LoadError {
     LoadError,
     RomType,
     RomSize,
+    NoRomInArchive, // A zip without a .gb or .gbc entry
 }
 
 pub type LoadResult = Result<i32, LoadError>;
 
+#[derive(Debug)]
+pub enum MBC {
+    RomOnly,
+    MBC1,
+    MBC2,
+    MBC3,
+    MBC5,
+}
+
+const MBC2_RAM_SIZE: usize = 512; // Built in, 4 bits per byte
+
+// Initial contents of the external RAM, for tests that need known SRAM
+pub enum RamInit<'a> {
+    Blank,          // All 0x00
+    Ones,           // All 0xff
+    Data(&'a [u8]), // Copied from the start, the rest is left blank
+}
+
+// http://gbdev.gg8.se/wiki/articles/Memory_Bank_Controllers
+pub struct Cartridge {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    mbc: MBC,
+    rom_bank: u8,   // 0x2000-0x3fff, lower bits of the ROM bank
+    rom_bank9: u8,  // 0x3000-0x3fff on MBC5, bit 8 of the ROM bank
+    bank_hi: u8,    // 0x4000-0x5fff, RAM bank or upper ROM bank bits
+    mode: bool,     // 0x6000-0x7fff, banking mode select
+    ram_enabled: bool, // 0x0000-0x1fff, RAM is only accessible after writing 0x0a
+    multicart: bool,
+    rumble: bool,   // Motor state on rumble carts, bit 3 of the RAM bank register
+    rtc: [u8; 5],   // MBC3 clock registers 0x08-0x0c, kept but not advanced
+}
+
+impl Cartridge {
+    pub fn new(rom: Vec<u8>, mbc: MBC, ram_size: usize) -> Cartridge {
+        let multicart = match mbc { MBC::MBC1 => is_multicart(&rom), _ => false };
+        Cartridge {
+            rom,
+            ram: vec![0; ram_size],
+            mbc,
+            rom_bank: 1,
+            rom_bank9: 0,
+            bank_hi: 0,
+            mode: false,
+            ram_enabled: false,
+            multicart,
+            rumble: false,
+            rtc: [0; 5],
+        }
+    }
+
+    pub fn empty() -> Cartridge {
+        Cartridge::new(vec![0; 0x8000], MBC::RomOnly, 0)
+    }
+
+    // Back to the power on bank mapping. RAM is kept, it's battery backed
+    pub fn reset_banks(&mut self) {
+        self.rom_bank = 1;
+        self.rom_bank9 = 0;
+        self.bank_hi = 0;
+        self.mode = false;
+        self.ram_enabled = false;
+        self.rumble = false;
+    }
+
+    // The ROM itself isn't part of a state, only RAM and the MBC registers
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        w.u8(self.rom_bank);
+        w.u8(self.rom_bank9);
+        w.u8(self.bank_hi);
+        w.bool(self.mode);
+        w.bool(self.ram_enabled);
+        w.bool(self.rumble);
+        w.bytes(&self.rtc);
+    }
+
+    pub fn state_size(&self) -> usize {
+        self.ram.len() + 6 + self.rtc.len()
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) {
+        r.bytes(&mut self.ram);
+        self.rom_bank = r.u8();
+        self.rom_bank9 = r.u8();
+        self.bank_hi = r.u8();
+        self.mode = r.bool();
+        self.ram_enabled = r.bool();
+        self.rumble = r.bool();
+        r.bytes(&mut self.rtc);
+    }
+
+    fn rom_banks(&self) -> usize {
+        cmp::max(self.rom.len() / 0x4000, 1)
+    }
+
+    // MBC1M multicarts only wire 4 bits of the ROM bank register
+    fn bank_shift(&self) -> usize {
+        if self.multicart { 4 } else { 5 }
+    }
+
+    // Bank mapped at 0x0000-0x3fff, only moved by the secondary register in mode 1
+    pub fn low_bank(&self) -> usize {
+        match self.mbc {
+            MBC::MBC1 if self.mode => ((self.bank_hi as usize) << self.bank_shift()) % self.rom_banks(),
+            _ => 0,
+        }
+    }
+
+    // Bank mapped at 0x4000-0x7fff, the secondary register applies in both modes
+    pub fn high_bank(&self) -> usize {
+        match self.mbc {
+            MBC::RomOnly => 1,
+            MBC::MBC1 => {
+                let low = if self.multicart { self.rom_bank & 0x0f } else { self.rom_bank };
+                (((self.bank_hi as usize) << self.bank_shift()) | low as usize) % self.rom_banks()
+            }
+            MBC::MBC2 => (self.rom_bank & 0x0f) as usize % self.rom_banks(),
+            MBC::MBC3 => (self.rom_bank & 0x7f) as usize % self.rom_banks(),
+            // Bank 0 can be mapped here too on MBC5
+            MBC::MBC5 => (((self.rom_bank9 as usize) << 8) | self.rom_bank as usize) % self.rom_banks(),
+        }
+    }
+
+    pub fn ram_bank(&self) -> usize {
+        match self.mbc {
+            MBC::MBC1 if self.mode => self.bank_hi as usize,
+            MBC::MBC3 => (self.bank_hi & 0x03) as usize,
+            // Rumble carts drive the motor with bit 3 instead
+            MBC::MBC5 if self.has_rumble() => (self.bank_hi & 0x07) as usize,
+            MBC::MBC5 => self.bank_hi as usize,
+            _ => 0,
+        }
+    }
+
+    // Describes the current mapping, for debugging banking issues
+    pub fn map_summary(&self) -> String {
+        format!("MBC: {:?}, banking mode {}\n\
+                 0000-3FFF: ROM bank {:02X}\n\
+                 4000-7FFF: ROM bank {:02X}\n\
+                 A000-BFFF: RAM bank {:02X}",
+                self.mbc, if self.mode { 1 } else { 0 },
+                self.low_bank(), self.high_bank(), self.ram_bank())
+    }
+
+    pub fn cartridge_type(&self) -> Option<CartridgeType> {
+        self.rom.get(ROM_TYPE_OFFSET as usize).and_then(|t| CartridgeType::from_byte(*t))
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.cartridge_type().is_some_and(|t| t.has_battery())
+    }
+
+    pub fn has_rtc(&self) -> bool {
+        self.cartridge_type().is_some_and(|t| t.has_rtc())
+    }
+
+    pub fn has_rumble(&self) -> bool {
+        self.cartridge_type().is_some_and(|t| t.has_rumble())
+    }
+
+    pub fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0x4000 { self.low_bank() } else { self.high_bank() };
+        let offset = bank * 0x4000 + (address as usize & 0x3fff);
+        match self.rom.get(offset) {
+            Some(v) => *v,
+            None => 0xff,
+        }
+    }
+
+    pub fn init_ram(&mut self, init: RamInit) {
+        match init {
+            RamInit::Blank => for b in self.ram.iter_mut() { *b = 0; },
+            RamInit::Ones => for b in self.ram.iter_mut() { *b = 0xff; },
+            RamInit::Data(data) => {
+                for b in self.ram.iter_mut() { *b = 0; }
+                let len = cmp::min(data.len(), self.ram.len());
+                self.ram[..len].copy_from_slice(&data[..len]);
+            }
+        }
+    }
+
+    pub fn rumble_state(&self) -> bool {
+        self.rumble
+    }
+
+    // Writes to 0x0000-0x7fff only drive the MBC registers, the ROM image itself
+    // is never modified
+    pub fn write_rom(&mut self, address: u16, value: u8) {
+        if (address & 0xe000) == 0x4000 && self.has_rumble() {
+            self.rumble = (value & 0x08) != 0;
+        }
+        match self.mbc {
+            MBC::RomOnly => {}
+            MBC::MBC1 => {
+                // Bank 0 can't be selected through the 5 bit register and maps to 1.
+                // Only those 5 bits are checked, so banks 0x20/0x40/0x60 read as
+                // 0x21/0x41/0x61 once the secondary register supplies bits 5-6
+                match address {
+                    0x0000 ..= 0x1fff => { self.ram_enabled = (value & 0x0f) == 0x0a; }
+                    0x2000 ..= 0x3fff => { self.rom_bank = if (value & 0x1f) == 0 { 1 } else { value & 0x1f }; }
+                    0x4000 ..= 0x5fff => { self.bank_hi = value & 0x03; }
+                    0x6000 ..= 0x7fff => { self.mode = (value & 0x01) != 0; }
+                    _ => {}
+                }
+            }
+            MBC::MBC2 => {
+                // Address bit 8 tells the RAM enable and ROM bank registers apart
+                match address {
+                    0x0000 ..= 0x3fff if (address & 0x0100) == 0 => { self.ram_enabled = (value & 0x0f) == 0x0a; }
+                    0x0000 ..= 0x3fff => { self.rom_bank = if (value & 0x0f) == 0 { 1 } else { value & 0x0f }; }
+                    _ => {}
+                }
+            }
+            MBC::MBC3 => {
+                match address {
+                    0x0000 ..= 0x1fff => { self.ram_enabled = (value & 0x0f) == 0x0a; }
+                    0x2000 ..= 0x3fff => { self.rom_bank = if (value & 0x7f) == 0 { 1 } else { value & 0x7f }; }
+                    0x4000 ..= 0x5fff => { self.bank_hi = value; }
+                    _ => {}     // Clock latch, the clock doesn't run
+                }
+            }
+            MBC::MBC5 => {
+                match address {
+                    0x0000 ..= 0x1fff => { self.ram_enabled = (value & 0x0f) == 0x0a; }
+                    0x2000 ..= 0x2fff => { self.rom_bank = value; }
+                    0x3000 ..= 0x3fff => { self.rom_bank9 = value & 0x01; }
+                    0x4000 ..= 0x5fff => { self.bank_hi = value & 0x0f; }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn rtc_register(&self) -> Option<usize> {
+        match self.bank_hi {
+            0x08 ..= 0x0c => Some((self.bank_hi - 0x08) as usize),
+            _ => None,
+        }
+    }
+
+    // Cartridges without an MBC have no enable register
+    fn ram_accessible(&self) -> bool {
+        match self.mbc {
+            MBC::RomOnly => true,
+            _ => self.ram_enabled,
+        }
+    }
+
+    // Without external RAM, or with it disabled, the bus floats high
+    pub fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_accessible() { return 0xff; }
+        match self.mbc {
+            // MBC2 RAM repeats every 512 bytes and only drives the low nibble
+            MBC::MBC2 => return 0xf0 | self.ram[address as usize & 0x1ff],
+            MBC::MBC3 if self.bank_hi >= 0x08 => return self.rtc_register().map_or(0xff, |r| self.rtc[r]),
+            _ => {}
+        }
+        if self.ram.is_empty() { return 0xff; }
+        let offset = (self.ram_bank() * 0x2000 + (address as usize - 0xa000)) % self.ram.len();
+        self.ram[offset]
+    }
+
+    pub fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_accessible() { return; }
+        match self.mbc {
+            MBC::MBC2 => { self.ram[address as usize & 0x1ff] = value & 0x0f; return; }
+            MBC::MBC3 if self.bank_hi >= 0x08 => {
+                if let Some(r) = self.rtc_register() { self.rtc[r] = value; }
+                return;
+            }
+            _ => {}
+        }
+        if self.ram.is_empty() { return; }
+        let offset = (self.ram_bank() * 0x2000 + (address as usize - 0xa000)) % self.ram.len();
+        self.ram[offset] = value;
+    }
+}
+
+// Multicarts repeat the Nintendo logo at the start of every 256KB game
+fn is_multicart(rom: &[u8]) -> bool {
+    rom.len() == 0x100000 && rom[0x104..0x134] == rom[0x40104..0x40134]
+}
+
 pub fn load_rom(filename: &str, mem: &mut Memory) -> LoadResult {       
     let mut data = vec![];
 
     let path = path::PathBuf::from(filename);
-    try!(File::open(&path).and_then(|mut f| f.read_to_end(&mut data)).map_err(|_| LoadError::LoadError));
+    File::open(&path).and_then(|mut f| f.read_to_end(&mut data)).map_err(|_| LoadError::LoadError)?;
+    let data = unpack(data)?;
+    load_rom_data(data, mem)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> usize {
+    data[offset] as usize | (data[offset + 1] as usize) << 8
+}
+
+fn read_u32(data: &[u8], offset: usize) -> usize {
+    read_u16(data, offset) | read_u16(data, offset + 2) << 16
+}
+
+// Extracts the ROM from gzip and zip files, anything else is returned as is
+pub fn unpack(data: Vec<u8>) -> Result<Vec<u8>, LoadError> {
+    if data.starts_with(&[0x1f, 0x8b]) {
+        unpack_gzip(&data).ok_or(LoadError::LoadError)
+    } else if data.starts_with(&[0x50, 0x4b, 0x03, 0x04]) {
+        unpack_zip(&data)
+    } else {
+        Ok(data)
+    }
+}
+
+// http://www.zlib.org/rfc-gzip.html
+fn unpack_gzip(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < 18 || data[2] != 8 { return None; }
+    let flags = data[3];
+    let mut pos = 10;
+    if (flags & 0x04) != 0 { pos += 2 + read_u16(data, pos); }  // FEXTRA
+    for &flag in &[0x08, 0x10] {                                // FNAME, FCOMMENT
+        if (flags & flag) != 0 {
+            while pos < data.len() && data[pos] != 0 { pos += 1; }
+            pos += 1;
+        }
+    }
+    if (flags & 0x02) != 0 { pos += 2; }                        // FHCRC
+    if pos + 8 > data.len() { return None; }
+    // The stream is followed by the CRC and the size of the uncompressed data
+    let end = data.len() - 8;
+    inflate(&data[pos..end]).and_then(|rom| {
+        let valid = read_u32(data, end) as u32 == crc32(&rom) && read_u32(data, end + 4) == rom.len() & 0xffffffff;
+        if valid { Some(rom) } else { None }
+    })
+}
+
+// Unpacks the first .gb/.gbc entry listed in the central directory
+// https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
+fn unpack_zip(data: &[u8]) -> Result<Vec<u8>, LoadError> {
+    if data.len() < 22 { return Err(LoadError::LoadError); }
+    let end = match (0..data.len() - 21).rev().find(|&i| data[i..].starts_with(&[0x50, 0x4b, 0x05, 0x06])) {
+        Some(i) => i,
+        None => return Err(LoadError::LoadError),
+    };
+    let entries = read_u16(data, end + 10);
+    let mut pos = read_u32(data, end + 16);
+    for _ in 0..entries {
+        if pos + 46 > data.len() || !data[pos..].starts_with(&[0x50, 0x4b, 0x01, 0x02]) { return Err(LoadError::LoadError); }
+        let method = read_u16(data, pos + 10);
+        let crc = read_u32(data, pos + 16) as u32;
+        let size = read_u32(data, pos + 20);
+        let name_len = read_u16(data, pos + 28);
+        let header = read_u32(data, pos + 42);
+        if pos + 46 + name_len > data.len() { return Err(LoadError::LoadError); }
+        let name = String::from_utf8_lossy(&data[pos + 46..pos + 46 + name_len]).to_lowercase();
+        pos += 46 + name_len + read_u16(data, pos + 30) + read_u16(data, pos + 32);
+
+        if !name.ends_with(".gb") && !name.ends_with(".gbc") { continue; }
+        if header + 30 > data.len() { return Err(LoadError::LoadError); }
+        let start = header + 30 + read_u16(data, header + 26) + read_u16(data, header + 28);
+        if start + size > data.len() { return Err(LoadError::LoadError); }
+        let contents = &data[start..start + size];
+        let rom = match method {
+            0 => Some(contents.to_vec()),
+            8 => inflate(contents),
+            _ => None,
+        };
+        return match rom {
+            Some(rom) if crc32(&rom) == crc => Ok(rom),
+            _ => Err(LoadError::LoadError),
+        };
+    }
+    Err(LoadError::NoRomInArchive)
+}
+
+pub fn load_rom_data(data: Vec<u8>, mem: &mut Memory) -> LoadResult {
+    use self::CartridgeType::*;
     if data.len() < 0x180 { 
         return Err(LoadError::RomSize)
     }
 
     let rom_type = data[ROM_TYPE_OFFSET as usize];
     println!("Romtype: {}", rom_type);
-    // if rom_type != CartridgeType::RomOnly as u8 {
-    //     return Err(LoadError::RomType)
-    // } 
 
     let mut name = String::with_capacity(16);
     for i in 0..16 {
@@ -76,16 +499,29 @@ pub fn load_rom(filename: &str, mem: &mut Memory) -> LoadResult {
         
     let romsize = rom_size(data[ROM_SIZE_OFFSET as usize]);
     println!("Romsize: {}", romsize * 16);
-    if romsize * 16 != 32 {
+    if romsize == 0 || data.len() < romsize as usize * 0x4000 {
         return Err(LoadError::RomSize);
     }
 
     let ramsize = ram_size(data[ROM_RAM_OFFSET as usize]);
     println!("Ram size: {}", ramsize);
 
-    for (idx, element) in data.into_iter().enumerate() {
-        mem.write_byte(idx as u16, element);
-    }
+    let mbc = match CartridgeType::from_byte(rom_type) {
+        Some(RomOnly) | Some(RomRam) | Some(RomRamBatt) => MBC::RomOnly,
+        Some(RomMBC1) | Some(RomMBC1Ram) | Some(RomMBC1RamBatt) => MBC::MBC1,
+        Some(RomMBC2) | Some(RomMBC2Batt) => MBC::MBC2,
+        Some(RomMBC3TimerBatt) | Some(RomMBC3TimerRamBatt) | Some(RomMBC3) |
+        Some(RomMBC3Ram) | Some(RomMBC3RamBatt) => MBC::MBC3,
+        Some(RomMBC5) | Some(RomMBC5Ram) | Some(RomMBC5RamBatt) | Some(RomMBC5Rumble) |
+        Some(RomMBC5RumbleSRam) | Some(RomMBC5RumbleSRamBatt) => MBC::MBC5,
+        _ => return Err(LoadError::RomType),
+    };
+    let ramsize = match mbc {
+        MBC::MBC2 => MBC2_RAM_SIZE,
+        _ => ramsize as usize * 1024,
+    };
+    mem.model = if (data[ROM_CGB_OFFSET as usize] & 0x80) != 0 { Model::CGB } else { Model::DMG };
+    mem.load_cartridge(Cartridge::new(data, mbc, ramsize));
 
     Ok(1)
 }
@@ -97,11 +533,12 @@ fn ram_size(size: u8) -> u8 {
         2 => 8,
         3 => 32,
         4 => 128,
+        5 => 64,
         _ => 0,
     }
 }
 
-fn rom_size(size: u8) -> u8 {
+fn rom_size(size: u8) -> u16 {
     match size {
         0 => 2,
         1 => 4,
@@ -110,6 +547,8 @@ fn rom_size(size: u8) -> u8 {
         4 => 32,
         5 => 64,
         6 => 128,
+        7 => 256,
+        8 => 512,
         0x52 => 72,
         0x53 => 80,
         0x54 => 96,
@@ -117,3 +556,185 @@ fn rom_size(size: u8) -> u8 {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ROM whose banks start with their own number. Only the first bank has a
+    // logo, so 1MB images aren't taken for multicarts
+    fn banked_rom(banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * 0x4000];
+        for bank in 0..banks {
+            rom[bank * 0x4000] = bank as u8;
+        }
+        rom[0x104] = 0xce;
+        rom
+    }
+
+    #[test]
+    fn mbc1_mode_1_banks_the_low_region() {
+        let mut cart = Cartridge::new(banked_rom(64), MBC::MBC1, 0);
+        cart.write_rom(0x4000, 0x01);
+        assert_eq!(cart.read_rom(0x0000), 0);
+        assert_eq!(cart.read_rom(0x4000), 0x21);
+        cart.write_rom(0x6000, 0x01);
+        assert_eq!(cart.read_rom(0x0000), 0x20);
+        assert_eq!(cart.read_rom(0x4000), 0x21);
+        cart.write_rom(0x6000, 0x00);
+        assert_eq!(cart.read_rom(0x0000), 0);
+    }
+
+    #[test]
+    fn map_summary_reports_the_selected_bank() {
+        let mut cart = Cartridge::new(banked_rom(8), MBC::MBC1, 0);
+        cart.write_rom(0x2000, 0x05);
+        assert!(cart.map_summary().contains("4000-7FFF: ROM bank 05"), "{}", cart.map_summary());
+    }
+
+    #[test]
+    fn missing_ram_reads_open_bus() {
+        let mut cart = Cartridge::new(banked_rom(2), MBC::MBC1, 0);
+        cart.write_rom(0x0000, 0x0a);
+        cart.write_ram(0xa000, 0x12);
+        assert_eq!(cart.read_ram(0xa000), 0xff);
+        assert_eq!(cart.read_ram(0xbfff), 0xff);
+    }
+
+    #[test]
+    fn header_type_reports_capabilities() {
+        let mbc3 = CartridgeType::RomMBC3TimerRamBatt;
+        assert!(mbc3.has_battery() && mbc3.has_rtc() && !mbc3.has_rumble());
+        let mbc5 = CartridgeType::RomMBC5RumbleSRamBatt;
+        assert!(mbc5.has_battery() && mbc5.has_rumble() && !mbc5.has_rtc());
+
+        let mut rom = banked_rom(2);
+        rom[0x147] = CartridgeType::RomMBC3TimerRamBatt as u8;
+        let cart = Cartridge::new(rom, MBC::MBC3, 0x2000);
+        assert!(cart.has_battery() && cart.has_rtc() && !cart.has_rumble());
+    }
+
+    #[test]
+    fn init_ram_loads_a_known_pattern() {
+        let pattern: Vec<u8> = (0..0x100).map(|i| (i * 7) as u8).collect();
+        let mut cart = Cartridge::new(banked_rom(2), MBC::MBC1, 0x2000);
+        cart.init_ram(RamInit::Data(&pattern));
+        cart.write_rom(0x0000, 0x0a);
+        for (i, &value) in pattern.iter().enumerate() {
+            assert_eq!(cart.read_ram(0xa000 + i as u16), value);
+        }
+        assert_eq!(cart.read_ram(0xa100), 0x00);
+
+        cart.init_ram(RamInit::Ones);
+        assert_eq!(cart.read_ram(0xa000), 0xff);
+    }
+
+    const GZIP_ROM: &[u8] = include_bytes!("../tests/fixtures/rom.gb.gz");
+    const ZIP_ROM: &[u8] = include_bytes!("../tests/fixtures/rom.zip");
+
+    // What both fixtures were compressed from
+    fn fixture_rom() -> Vec<u8> {
+        let mut rom: Vec<u8> = (0..4096u32).map(|i| (i * i / 7) as u8).collect();
+        for _ in 0..128 {
+            rom.extend_from_slice(b"RustBoy ");
+        }
+        rom
+    }
+
+    #[test]
+    fn unpack_gzip_and_zip() {
+        assert!(unpack(GZIP_ROM.to_vec()).unwrap() == fixture_rom());
+        // The zip also holds a readme, only the .gb entry is taken
+        assert!(unpack(ZIP_ROM.to_vec()).unwrap() == fixture_rom());
+        assert!(unpack(fixture_rom()).unwrap() == fixture_rom());
+    }
+
+    #[test]
+    fn unpack_rejects_corrupt_archives() {
+        for fixture in &[GZIP_ROM, ZIP_ROM] {
+            for len in 4..fixture.len() - 1 {
+                assert!(unpack(fixture[..len].to_vec()).is_err(), "truncated to {}", len);
+            }
+            for i in 4..fixture.len() {
+                for &bit in &[0x01, 0x80] {
+                    let mut data = fixture.to_vec();
+                    data[i] ^= bit;
+                    // Flips in fields like the timestamp or the other entry are harmless
+                    if let Ok(rom) = unpack(data) {
+                        assert!(rom == fixture_rom(), "flipped {:02x} at {}", bit, i);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cgb_flag_selects_the_cgb_model() {
+        for &(flag, key1) in &[(0x00, 0xff), (0x80, 0x7e), (0xc0, 0x7e)] {
+            let mut rom = banked_rom(2);
+            rom[ROM_CGB_OFFSET as usize] = flag;
+            let mut mem = Memory::headless();
+            load_rom_data(rom, &mut mem).unwrap();
+            assert_eq!(mem.read_byte(0xff4d), key1, "flag {:02x}", flag);
+        }
+    }
+
+    #[test]
+    fn zip_without_a_rom_is_reported() {
+        // An empty archive, only the end of central directory record
+        let mut zip = vec![0x50, 0x4b, 0x03, 0x04];
+        zip.extend_from_slice(&[0x50, 0x4b, 0x05, 0x06]);
+        zip.extend_from_slice(&[0; 18]);
+        match unpack(zip) {
+            Err(LoadError::NoRomInArchive) => {}
+            other => panic!("{:?}", other.map(|rom| rom.len())),
+        }
+    }
+
+    #[test]
+    fn disabled_ram_reads_0xff() {
+        let mut cart = Cartridge::new(banked_rom(2), MBC::MBC1, 0x8000);
+        cart.write_rom(0x0000, 0x0a);
+        cart.write_ram(0xa000, 0x12);
+        assert_eq!(cart.read_ram(0xa000), 0x12);
+        cart.write_rom(0x0000, 0x00);
+        assert_eq!(cart.read_ram(0xa000), 0xff);
+        cart.write_ram(0xa000, 0x34);
+        cart.write_rom(0x0000, 0x0a);
+        assert_eq!(cart.read_ram(0xa000), 0x12);
+    }
+
+    #[test]
+    fn mbc5_reaches_bank_0x100() {
+        let mut rom = banked_rom(0x200);
+        rom[0x100 * 0x4000 + 1] = 0xab;
+        let mut cart = Cartridge::new(rom, MBC::MBC5, 0);
+        cart.write_rom(0x2000, 0x00);
+        cart.write_rom(0x3000, 0x01);
+        assert_eq!(cart.read_rom(0x4001), 0xab);
+        // Bank 0 can be mapped too, unlike on MBC1
+        cart.write_rom(0x3000, 0x00);
+        assert_eq!(cart.read_rom(0x4000), 0x00);
+        assert_eq!(cart.read_rom(0x4001), 0x00);
+    }
+
+    #[test]
+    fn header_type_selects_the_controller() {
+        let mut rom = banked_rom(2);
+        rom[ROM_TYPE_OFFSET as usize] = 0x01;
+        let mut mem = Memory::headless();
+        assert!(load_rom_data(rom.clone(), &mut mem).is_ok());
+        assert!(mem.cartridge().map_summary().starts_with("MBC: MBC1,"), "{}", mem.cartridge().map_summary());
+
+        assert!(CartridgeType::from_byte(0x1f).is_none());
+        match CartridgeType::from_byte(0xfc) {
+            Some(CartridgeType::PocketCamera) => {}
+            _ => panic!("0xfc is the Pocket Camera"),
+        }
+        rom[ROM_TYPE_OFFSET as usize] = 0xfc; // Pocket Camera
+        match load_rom_data(rom, &mut mem) {
+            Err(LoadError::RomType) => {}
+            _ => panic!("Pocket Camera carts aren't supported"),
+        }
+    }
+}
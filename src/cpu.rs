@@ -2,17 +2,21 @@
 use registers::Flags::{Z, N, H, C};
 use memory::Memory;
 use cartridge;
+use gpu::{FRAME_CYCLES, PixelFormat, FrameSink};
+use overlay;
+use movie::Movie;
+use disasm::{self, SymbolTable};
+use input::Button;
+use state::{self, StateError, StateReader, StateWriter};
 
-use sdl2::render::Renderer;
 
 use std::io;
-use std::io::prelude::*;
-use std::fs::File;
-use std::io::BufWriter;
-use std::fs::OpenOptions;
-use std::path;
+use std::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
 
 // https://realboyemulator.files.wordpress.com/2013/01/gbcpuman.pdf Page 34
+#[derive(Copy, Clone, Debug)]
 pub enum IFlags {
     VBLANK          = 0b00000001,
     LCDCSTATUS      = 0b00000010,
@@ -21,172 +25,577 @@ pub enum IFlags {
     KEYPAD          = 0b00010000,
 }
 
+// Handler addresses, in IFlags bit order which is also the priority order
+const INTERRUPT_VECTORS: [u16; 5] = [0x40, 0x48, 0x50, 0x58, 0x60];
+
+// Read-only view of the machine state for debugging frontends
+pub struct Snapshot {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub scanline: u8,
+    pub gpu_mode: u8,
+    pub ime: bool,      // Only set once the instruction after EI has run
+}
+
+// Raised by CPU::run_with_stack_floor
+#[derive(Debug)]
+pub struct StackOverflow {
+    pub pc: u16,    // Instruction that moved SP below the floor
+    pub sp: u16,
+}
+
 pub struct CPU {
     register: Registers,
     memory: Memory,
-    ticks: u32,
+    ticks: u32,         // Cycles into the current frame
+    total_cycles: u64,  // Cycles since power on
     stopped: bool,
     halted: bool,
+    halt_bug: bool,
+    ime_pending: u8,    // Instructions left until a pending EI takes effect
     debugging: bool,
+    cycle_stepped: bool, // Advance the clocks at every bus access, see access_tick
+    step_ticks: u32,    // Cycles already advanced within the current step
+    step_vblank: bool,
+    rewind: VecDeque<Vec<u8>>, // Save states, most recent last
+    rewind_interval: u32, // Frames between two captured states, 0 disables rewinding
+    rewind_capacity: usize,
+    rewind_countdown: u32,
 }
 
 #[allow(dead_code)]
 impl CPU {
-    pub fn new(rend: Renderer<'static>) -> CPU {
+    pub fn new(sink: Box<dyn FrameSink>) -> CPU {
+        CPU::with_memory(Memory::new(sink))
+    }
+
+    // Runs without SDL, frames only end up in the framebuffer. For tests and
+    // other frontends driving the CPU through step and peek/poke
+    pub fn new_headless() -> CPU {
+        CPU::with_memory(Memory::headless())
+    }
+
+    fn with_memory(memory: Memory) -> CPU {
         CPU {
             register: Registers::new(),
-            memory: Memory::new(rend),            
+            memory,
             ticks: 0,
+            total_cycles: 0,
             stopped: false,
             halted: false,
+            halt_bug: false,
+            ime_pending: 0,
             debugging: false,
+            cycle_stepped: false,
+            step_ticks: 0,
+            step_vblank: false,
+            rewind: VecDeque::new(),
+            rewind_interval: 0,
+            rewind_capacity: 0,
+            rewind_countdown: 0,
         }
     }
 
     pub fn initialize(&mut self, filename: &str) {
         match cartridge::load_rom(filename, &mut self.memory) {
-            Ok(n) => println!("Rom loaded successfully!"),
+            Ok(_) => println!("Rom loaded successfully!"),
             Err(err) => println!("Error: {:?}", err),
         }
         self.memory.put_initial();
     }
 
-    pub fn cpu_cycle(&mut self) {
-        if self.stopped { return; }
-        self.ticks += self.execute() as u32;
-        self.memory.gpu_cycle(self.ticks);
-        self.interrupt_cycle();
-    }
-
-    pub fn interrupt_cycle(&mut self) {
-        if self.memory.master && self.memory.enable != 0 && self.memory.flags != 0 {
-            let trigger = self.memory.enable & self.memory.flags;
-
-            if (trigger & IFlags::VBLANK as u8) != 0 {
-                self.memory.flags &= !(IFlags::VBLANK as u8);
-                self.vblank();
-                self.memory.master = false;
-            }  
-            
-            if (trigger & IFlags::LCDCSTATUS as u8) != 0 {
-                self.memory.flags &= !(IFlags::LCDCSTATUS as u8);
-                self.lcd_status();
-                self.memory.master = false;
-            }    
-            
-            if (trigger & IFlags::TIMEROVERFLOW as u8) != 0 {
-                self.memory.flags &= !(IFlags::TIMEROVERFLOW as u8);
-                self.timer_overflow();
-                self.memory.master = false;
+    pub fn load_rom_data(&mut self, data: Vec<u8>) -> cartridge::LoadResult {
+        let result = cartridge::load_rom_data(data, &mut self.memory);
+        self.reset();
+        result
+    }
+
+    pub fn set_pc(&mut self, address: u16) {
+        self.register.PC = address;
+    }
+
+    // Loads raw code at `address` and starts executing it at `entry`, bypassing
+    // the cartridge header. Bytes landing in ROM space replace the cartridge
+    // with a blank ROM only one.
+    pub fn load_raw(&mut self, data: &[u8], address: u16, entry: u16) {
+        self.reset();
+        let mut rom = vec![0; 0x8000];
+        let mut in_rom = false;
+        for (i, b) in data.iter().enumerate() {
+            let addr = address.wrapping_add(i as u16);
+            if addr < 0x8000 {
+                rom[addr as usize] = *b;
+                in_rom = true;
+            } else {
+                self.memory.write_byte(addr, *b);
+            }
+        }
+        if in_rom {
+            self.memory.load_cartridge(cartridge::Cartridge::new(rom, cartridge::MBC::RomOnly, 0));
+        }
+        self.set_pc(entry);
+    }
+
+    pub fn reset(&mut self) {
+        self.register.reset();
+        self.memory.reset();
+        self.ticks = 0;
+        self.total_cycles = 0;
+        self.stopped = false;
+        self.halted = false;
+        self.halt_bug = false;
+        self.ime_pending = 0;
+        self.rewind.clear();
+        self.rewind_countdown = self.rewind_interval;
+    }
+
+    // Snapshot of the whole machine except the ROM, see state.rs for the layout
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        for &r in &[self.register.A, self.register.F, self.register.B, self.register.C,
+                    self.register.D, self.register.E, self.register.H, self.register.L] {
+            w.u8(r);
+        }
+        w.u16(self.register.SP);
+        w.u16(self.register.PC);
+        w.u32(self.ticks);
+        w.u64(self.total_cycles);
+        w.bool(self.stopped);
+        w.bool(self.halted);
+        w.bool(self.halt_bug);
+        w.u8(self.ime_pending);
+        self.memory.save_state(&mut w);
+        w.finish()
+    }
+
+    // Length of the save_state output with the loaded cartridge
+    pub fn state_size(&self) -> usize {
+        state::HEADER_SIZE + 28 + self.memory.state_size()
+    }
+
+    // Restores a state saved with the same cartridge loaded. Nothing is changed
+    // when the data doesn't fit
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(data)?;
+        if data.len() != self.state_size() {
+            return Err(StateError::BadSize);
+        }
+        self.register.A = r.u8();
+        self.register.F = r.u8() & 0xf0;
+        self.register.B = r.u8();
+        self.register.C = r.u8();
+        self.register.D = r.u8();
+        self.register.E = r.u8();
+        self.register.H = r.u8();
+        self.register.L = r.u8();
+        self.register.SP = r.u16();
+        self.register.PC = r.u16();
+        self.ticks = r.u32();
+        self.total_cycles = r.u64();
+        self.stopped = r.bool();
+        self.halted = r.bool();
+        self.halt_bug = r.bool();
+        self.ime_pending = r.u8();
+        self.memory.load_state(&mut r);
+        Ok(())
+    }
+
+    pub fn framebuffer(&self) -> &[u8] {
+        self.memory.gpu.framebuffer()
+    }
+
+    pub fn frame_ready(&self) -> bool {
+        self.memory.gpu.frame_ready()
+    }
+
+    pub fn clear_frame_ready(&mut self) {
+        self.memory.gpu.clear_frame_ready();
+    }
+
+    pub fn frame_as(&self, format: PixelFormat) -> Vec<u8> {
+        self.memory.gpu.frame_as(format)
+    }
+
+    pub fn frame_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.framebuffer().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // Replays a movie from power on, one frame per input, without any wall clock
+    // involvement. Returns the framebuffer hash of every frame so two runs can be
+    // compared for determinism. Expects a freshly loaded cartridge.
+    pub fn play_movie(&mut self, filename: &str) -> io::Result<Vec<u64>> {
+        let movie = Movie::load(filename)?;
+        Ok(self.run_movie(&movie))
+    }
+
+    pub fn run_movie(&mut self, movie: &Movie) -> Vec<u64> {
+        self.reset();
+        self.memory.init_cart_ram(cartridge::RamInit::Blank);
+        self.memory.seed_ram(movie.seed);
+        let mut hashes = Vec::with_capacity(movie.frames.len());
+        for buttons in &movie.frames {
+            self.set_joypad(*buttons);
+            self.run_until_vblank();
+            hashes.push(self.frame_hash());
+        }
+        hashes
+    }
+
+    // Plays the movie twice and compares the frame hashes. Returns the first
+    // frame that differs, i.e. None when the emulation is deterministic
+    pub fn check_determinism(&mut self, movie: &Movie) -> Option<usize> {
+        let first = self.run_movie(movie);
+        let second = self.run_movie(movie);
+        first.iter().zip(second.iter()).position(|(a, b)| a != b)
+    }
+
+    pub fn set_joypad(&mut self, state: u8) {
+        self.memory.set_joypad(state);
+    }
+
+    pub fn rumble_state(&self) -> bool {
+        self.memory.rumble_state()
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.memory.set_button(button, pressed);
+    }
+
+    // Returns true when the GPU entered VBlank during this cycle
+    pub fn cpu_cycle(&mut self) -> bool {
+        // STOP ends once a button of a selected P1 group is pressed
+        if self.stopped {
+            if !self.memory.joypad_line_low() { return false; }
+            self.stopped = false;
+        }
+        self.step_ticks = 0;
+        self.step_vblank = false;
+        // While halted the CPU idles until an interrupt is pending. PC already
+        // points past HALT, so a serviced interrupt returns to the next instruction
+        let cycles = if self.halted {
+            if (self.memory.enable & self.memory.flags & 0x1f) != 0 {
+                self.halted = false;
             }
-            
-            if (trigger & IFlags::SERIALTC as u8) != 0 {
-                self.memory.flags &= !(IFlags::SERIALTC as u8);
-                self.serial_transf_complete();
-                self.memory.master = false;
-            }     
-
-            if (trigger & IFlags::KEYPAD as u8) != 0 {
-                self.memory.flags &= !(IFlags::KEYPAD as u8);
-                self.keypad();
-                self.memory.master = false;
+            4
+        } else {
+            self.execute() as u32
+        };
+        let mut vblank = self.finish_step(cycles);
+        let dma = self.memory.take_dma_ticks();
+        if dma != 0 {
+            vblank |= self.tick(dma);
+        }
+        // An interrupt raised during the EI window is serviced right after the
+        // following instruction, unless that instruction was DI
+        if self.ime_pending > 0 {
+            self.ime_pending -= 1;
+            if self.ime_pending == 0 { self.memory.master = true; }
+        }
+        self.step_ticks = 0;
+        self.step_vblank = false;
+        let irq = self.interrupt_cycle();
+        if irq != 0 {
+            vblank |= self.finish_step(irq);
+        }
+        if vblank && self.rewind_interval != 0 {
+            self.capture_rewind();
+        }
+        vblank
+    }
+
+    // Keeps a state every `interval` frames, at most `capacity` of them. A state
+    // is about 70KB, so e.g. one per 10 frames for 60 states covers 10 seconds
+    // in about 4MB. An interval of 0 turns rewinding off
+    pub fn set_rewind(&mut self, interval: u32, capacity: usize) {
+        self.rewind_interval = interval;
+        self.rewind_capacity = capacity;
+        self.rewind_countdown = interval;
+        self.rewind.clear();
+    }
+
+    fn capture_rewind(&mut self) {
+        self.rewind_countdown -= 1;
+        if self.rewind_countdown > 0 { return; }
+        self.rewind_countdown = self.rewind_interval;
+        if self.rewind_capacity == 0 { return; }
+        if self.rewind.len() == self.rewind_capacity {
+            self.rewind.pop_front();
+        }
+        let state = self.save_state();
+        self.rewind.push_back(state);
+    }
+
+    // Goes back to the most recent buffered state, false when there is none
+    pub fn rewind(&mut self) -> bool {
+        match self.rewind.pop_back() {
+            Some(state) => {
+                self.rewind_countdown = self.rewind_interval;
+                self.load_state(&state).is_ok()
             }
+            None => false,
         }
     }
 
-    fn vblank(&mut self){
-        self.memory.master = false;
+    pub fn rewind_available(&self) -> bool {
+        !self.rewind.is_empty()
+    }
+
+    pub fn rewind_len(&self) -> usize {
+        self.rewind.len()
+    }
+
+    // Opt-in mode where the GPU, timers and OAM DMA advance 4 cycles at each
+    // memory access of an instruction, so every access sees the hardware state
+    // of its own M-cycle instead of the state before the instruction. OAM DMA
+    // then copies a byte per M-cycle instead of all at once.
+    pub fn set_cycle_stepped(&mut self, enabled: bool) {
+        self.cycle_stepped = enabled;
+        self.memory.cycle_dma = enabled;
+    }
+
+    fn access_tick(&mut self) {
+        if self.cycle_stepped {
+            self.step_vblank |= self.tick(4);
+            self.step_ticks += 4;
+        }
+    }
+
+    // Advances the cycles of a step not yet spent on bus accesses
+    fn finish_step(&mut self, cycles: u32) -> bool {
+        let rest = cycles.saturating_sub(self.step_ticks);
+        let vblank = self.step_vblank | self.tick(rest);
+        self.step_ticks = 0;
+        self.step_vblank = false;
+        vblank
+    }
+
+    // Advances the clocks and the GPU by the cycles of a single step
+    fn tick(&mut self, cycles: u32) -> bool {
+        self.ticks = (self.ticks + cycles) % FRAME_CYCLES;
+        self.total_cycles += cycles as u64;
+        self.memory.timer_cycle(cycles);
+        self.memory.serial_cycle(cycles);
+        self.memory.dma_cycle(cycles);
+        self.memory.gpu_cycle(cycles)
+    }
+
+    // Runs the CPU until the next VBlank and returns the number of cycles spent.
+    // Bounded to one frame so the frontend keeps pumping events even if the
+    // LCD never reaches VBlank (e.g. while the CPU is stopped).
+    pub fn run_until_vblank(&mut self) -> u32 {
+        let start = self.total_cycles;
+        loop {
+            if self.cpu_cycle() { break; }
+            if self.stopped { break; }
+            if self.total_cycles - start >= FRAME_CYCLES as u64 { break; }
+        }
+        (self.total_cycles - start) as u32
+    }
+
+    // Like run_until_vblank for a number of frames, but fails as soon as SP drops
+    // below `floor`. Turns a runaway push loop into an error pointing at its PC.
+    pub fn run_with_stack_floor(&mut self, frames: u32, floor: u16) -> Result<(), StackOverflow> {
+        for _ in 0..frames {
+            let start = self.total_cycles;
+            loop {
+                let pc = self.register.PC;
+                let vblank = self.cpu_cycle();
+                if self.stopped { break; }
+                if self.register.SP < floor {
+                    return Err(StackOverflow { pc, sp: self.register.SP });
+                }
+                if vblank { break; }
+                if self.total_cycles - start >= FRAME_CYCLES as u64 { break; }
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the frame completed at the next VBlank. Unlike reading the pixel
+    // buffer directly this never yields a partially rendered screen.
+    pub fn capture_next_frame(&mut self) -> [u8; 160 * 144] {
+        self.run_until_vblank();
+        self.memory.gpu.pixel_buffer()
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn set_debugging(&mut self, debugging: bool) {
+        self.debugging = debugging;
+    }
+
+    // Reads memory on behalf of debugging tools
+    pub fn peek(&mut self, address: u16) -> u8 {
+        self.memory.read_byte(address)
+    }
+
+    // Receives every byte the game sends over the link port
+    pub fn set_serial_output(&mut self, output: Box<dyn FnMut(u8)>) {
+        self.memory.serial.set_output(output);
+    }
+
+    pub fn poke(&mut self, address: u16, value: u8) {
+        self.memory.write_byte(address, value);
+    }
+
+    // Runs one instruction, including a following interrupt dispatch, and
+    // returns the cycles it took
+    pub fn step(&mut self) -> u32 {
+        let start = self.total_cycles;
+        self.cpu_cycle();
+        (self.total_cycles - start) as u32
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.total_cycles
+    }
+
+    pub fn present_frame(&mut self) {
         self.memory.gpu.draw_framebuffer();
-        let pc = self.register.PC;
-        self.push_stack(pc);
-        self.register.PC = 0x40;
-        self.ticks += 36;
     }
 
-    fn lcd_status(&mut self) {
-        self.memory.master = false;
-        let pc = self.register.PC;
-        self.push_stack(pc);
-        self.register.PC = 0x48;
-        self.ticks += 36;
+    pub fn toggle_frame_blend(&mut self) {
+        self.memory.gpu.frame_blend = !self.memory.gpu.frame_blend;
     }
 
-    fn timer_overflow(&mut self) {
-        self.memory.master = false;
-        let pc = self.register.PC;
-        self.push_stack(pc);
-        self.register.PC = 0x50;
-        self.ticks += 36;
+    pub fn toggle_fullscreen(&mut self) {
+        self.memory.gpu.toggle_fullscreen();
     }
 
-    fn serial_transf_complete(&mut self) {
-        self.memory.master = false;
-        let pc = self.register.PC;
-        self.push_stack(pc);
-        self.register.PC = 0x58;
-        self.ticks += 36;
+    pub fn present_frame_with_overlay(&mut self, fps: u32) {
+        let snap = self.snapshot();
+        let mut frame = self.memory.gpu.output_frame();
+        overlay::draw(&mut frame, &snap, fps);
+        self.memory.gpu.draw_buffer(&frame);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            a: self.register.A,
+            f: self.register.F,
+            b: self.register.B,
+            c: self.register.C,
+            d: self.register.D,
+            e: self.register.E,
+            h: self.register.H,
+            l: self.register.L,
+            sp: self.register.SP,
+            pc: self.register.PC,
+            scanline: self.memory.gpu.scanline,
+            gpu_mode: self.memory.gpu.mode(),
+            ime: self.memory.master,
+        }
+    }
+
+    // Disassembles the instruction at `address`, returning the text and its length
+    pub fn disassemble_at(&mut self, address: u16, symbols: Option<&SymbolTable>) -> (String, u16) {
+        let bytes = [self.memory.read_byte(address),
+                     self.memory.read_byte(address.wrapping_add(1)),
+                     self.memory.read_byte(address.wrapping_add(2))];
+        disasm::disassemble(&bytes, address, symbols)
+    }
+
+    // Returns the cycles spent dispatching interrupts
+    pub fn interrupt_cycle(&mut self) -> u32 {
+        let trigger = self.memory.enable & self.memory.flags & 0x1f;
+        if !self.memory.master || trigger == 0 { return 0; }
+
+        // Only the highest priority interrupt (lowest bit) is serviced. Servicing
+        // clears IME, so the others wait until the handler re-enables it with EI/RETI
+        self.service_interrupt(trigger.trailing_zeros() as usize);
+        36
     }
 
-    fn keypad(&mut self) {
+    fn service_interrupt(&mut self, index: usize) {
         self.memory.master = false;
+        self.memory.flags &= !(1 << index);
         let pc = self.register.PC;
         self.push_stack(pc);
-        self.register.PC = 0x60;
-        self.ticks += 36;
+        self.register.PC = INTERRUPT_VECTORS[index];
+    }
+
+    // Instructions access memory through these. In cycle-stepped mode every
+    // access takes its own machine cycle, in order
+    fn read_byte(&mut self, address: u16) -> u8 {
+        let value = self.memory.read_byte(address);
+        self.access_tick();
+        value
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) {
+        self.memory.write_byte(address, value);
+        self.access_tick();
+    }
+
+    fn read_short(&mut self, address: u16) -> u16 {
+        let low = self.read_byte(address) as u16;
+        low | (self.read_byte(address.wrapping_add(1)) as u16) << 8
+    }
+
+    fn write_short(&mut self, address: u16, value: u16) {
+        self.write_byte(address, value as u8);
+        self.write_byte(address.wrapping_add(1), (value >> 8) as u8);
     }
 
     fn getbyte(&mut self) -> u8 {
-        let op = self.memory.read_byte(self.register.PC);
-        self.register.PC += 1;
+        let pc = self.register.PC;
+        let op = self.read_byte(pc);
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.register.PC = self.register.PC.wrapping_add(1);
+        }
         op
     }
 
     fn getshort(&mut self) -> u16 {
-        let op = self.memory.read_short(self.register.PC);
-        self.register.PC += 2;
+        let pc = self.register.PC;
+        let op = self.read_short(pc);
+        self.register.PC = self.register.PC.wrapping_add(2);
         op
     }
 
+    // The high byte is pushed first, after a cycle spent decrementing SP
     fn push_stack(&mut self, value: u16) {
-        self.register.SP -= 2; // Stack grows downwards
-        self.memory.write_short(self.register.SP, value);
+        self.access_tick();
+        self.register.SP = self.register.SP.wrapping_sub(1); // Stack grows downwards
+        let sp = self.register.SP;
+        self.write_byte(sp, (value >> 8) as u8);
+        self.register.SP = self.register.SP.wrapping_sub(1);
+        let sp = self.register.SP;
+        self.write_byte(sp, value as u8);
     }
 
     fn pop_stack(&mut self) -> u16 {
-        let v = self.memory.read_short(self.register.SP);
+        let sp = self.register.SP;
+        let v = self.read_short(sp);
         if self.debugging {
             println!("Read {:x} from stack", v);
         }
-        self.register.SP += 2;
+        self.register.SP = self.register.SP.wrapping_add(2);
         v
     }
 
+    #[allow(clippy::let_and_return)]
     fn execute(&mut self) -> u16 {
-        
-
-        if self.register.SP == 0xcff7 {
-            self.register.debug_register();
-            self.memory.debug_memory();
-            let mut input = String::new();
-            match io::stdin().read_line(&mut input) {
-                Ok(n) => {  }
-                Err(error) => println!("error: {}", error),
-            }
-            //self.debugging = true;
-            self.memory.gpu.render_scanline();
-        }
-
         let op = self.getbyte();
         //println!("{:X}", op); 
-        if self.debugging {
-            let mut input = String::new();
-            match io::stdin().read_line(&mut input) {
-                Ok(n) => {  }
-                Err(error) => println!("error: {}", error),
-            }
-            println!("{:X}", op);      
-            self.register.debug_register();
-        }
 
         match op {
             0x00 => {                                   self.nop();         4 }
@@ -213,7 +622,7 @@ impl CPU {
             0x15 => {                                   self.dec_d();       4 }
             0x16 => { let v = self.getbyte();           self.ld_d_n(v);     8 }
             0x17 => {                                   self.rla();         4 }
-            0x18 => { let v = self.getbyte() as i8;     self.jr_n(v);       8 }
+            0x18 => { let v = self.getbyte() as i8;     self.jr_n(v);       12 }
             0x19 => { let v = self.register.get_de();   self.add_hl_de(v);  8 }
             0x1a => {                                   self.ld_a_de();     8 }
             0x1b => {                                   self.dec_de();      8 }
@@ -221,7 +630,7 @@ impl CPU {
             0x1d => {                                   self.dec_e();       4 }
             0x1e => { let v = self.getbyte();           self.ld_e_n(v);     8 }
             0x1f => {                                   self.rra();         4 }
-            0x20 => { let v = self.getbyte() as i8;     self.jr_nz_n(v);    8 }
+            0x20 => { let v = self.getbyte() as i8;     if self.jr_nz_n(v) { 12 } else { 8 } }
             0x21 => { let v = self.getshort();          self.ld_hl_nn(v);   12 }
             0x22 => {                                   self.ldi_hl_a();    8 }
             0x23 => {                                   self.inc_hl();      8 }
@@ -229,7 +638,7 @@ impl CPU {
             0x25 => {                                   self.dec_h();       4 }
             0x26 => { let v = self.getbyte();           self.ld_h_n(v);     8 }
             0x27 => {                                   self.daa();         4 }
-            0x28 => { let v = self.getbyte() as i8;     self.jr_z_n(v);     8 }
+            0x28 => { let v = self.getbyte() as i8;     if self.jr_z_n(v) { 12 } else { 8 } }
             0x29 => { let v = self.register.get_hl();   self.add_hl_hl(v);  8 }
             0x2a => {                                   self.ldi_a_hl();    8 }
             0x2b => {                                   self.dec_hl();      8 }
@@ -237,7 +646,7 @@ impl CPU {
             0x2d => {                                   self.dec_l();       4 }
             0x2e => { let v = self.getbyte();           self.ld_l_n(v);     8 }
             0x2f => {                                   self.cpl();         4 }
-            0x30 => { let v = self.getbyte() as i8;     self.jr_nc_n(v);    8 }
+            0x30 => { let v = self.getbyte() as i8;     if self.jr_nc_n(v) { 12 } else { 8 } }
             0x31 => { let v = self.getshort();          self.ld_sp_nn(v);   12 }
             0x32 => {                                   self.ldd_hl_a();    8 }
             0x33 => {                                   self.inc_sp();      8 }
@@ -245,7 +654,7 @@ impl CPU {
             0x35 => {                                   self.dec_hl_ptr();  12 }
             0x36 => { let v = self.getbyte();           self.ld_hl_n(v);    12 }
             0x37 => {                                   self.scf();         4 }
-            0x38 => { let v = self.getbyte() as i8;     self.jr_c_n(v);     8 }
+            0x38 => { let v = self.getbyte() as i8;     if self.jr_c_n(v) { 12 } else { 8 } }
             0x39 => { let v = self.register.SP;         self.add_hl_sp(v);  8 }
             0x3a => {                                   self.ldd_a_hl();    8 }
             0x3b => {                                   self.dec_sp();      8 }
@@ -253,6 +662,7 @@ impl CPU {
             0x3d => {                                   self.dec_a();       4 }
             0x3e => { let v = self.getbyte();           self.ld_a_n(v);     8 }
             0x3f => {                                   self.ccf();         4 }
+            0x40 => {                                   self.ld_b_b();      4 }
             0x41 => {                                   self.ld_b_c();      4 }
             0x42 => {                                   self.ld_b_d();      4 }
             0x43 => {                                   self.ld_b_e();      4 }
@@ -261,6 +671,7 @@ impl CPU {
             0x46 => {                                   self.ld_b_hl();     8 }
             0x47 => {                                   self.ld_b_a();      4 }
             0x48 => {                                   self.ld_c_b();      4 }
+            0x49 => {                                   self.ld_c_c();      4 }
             0x4a => {                                   self.ld_c_d();      4 }
             0x4b => {                                   self.ld_c_e();      4 }
             0x4c => {                                   self.ld_c_h();      4 }
@@ -269,6 +680,7 @@ impl CPU {
             0x4f => {                                   self.ld_c_a();      4 }
             0x50 => {                                   self.ld_d_b();      4 }
             0x51 => {                                   self.ld_d_c();      4 }
+            0x52 => {                                   self.ld_d_d();      4 }
             0x53 => {                                   self.ld_d_e();      4 }
             0x54 => {                                   self.ld_d_h();      4 }
             0x55 => {                                   self.ld_d_l();      4 }
@@ -277,6 +689,7 @@ impl CPU {
             0x58 => {                                   self.ld_e_b();      4 }
             0x59 => {                                   self.ld_e_c();      4 }
             0x5a => {                                   self.ld_e_d();      4 }
+            0x5b => {                                   self.ld_e_e();      4 }
             0x5c => {                                   self.ld_e_h();      4 }
             0x5d => {                                   self.ld_e_l();      4 }
             0x5e => {                                   self.ld_e_hl();     8 }
@@ -285,6 +698,7 @@ impl CPU {
             0x61 => {                                   self.ld_h_c();      4 }
             0x62 => {                                   self.ld_h_d();      4 }
             0x63 => {                                   self.ld_h_e();      4 }
+            0x64 => {                                   self.ld_h_h();      4 }
             0x65 => {                                   self.ld_h_l();      4 }
             0x66 => {                                   self.ld_h_hl();     8 }
             0x67 => {                                   self.ld_h_a();      4 }
@@ -293,6 +707,7 @@ impl CPU {
             0x6a => {                                   self.ld_l_d();      4 }
             0x6b => {                                   self.ld_l_e();      4 }
             0x6c => {                                   self.ld_l_h();      4 }
+            0x6d => {                                   self.ld_l_l();      4 }
             0x6e => {                                   self.ld_l_hl();     8 }
             0x6f => {                                   self.ld_l_a();      4 }
             0x70 => {                                   self.ld_hl_b();     8 }
@@ -375,33 +790,33 @@ impl CPU {
             0xbd => {                                   self.cp_l();        4 }
             0xbe => {                                   self.cp_hl();       8 }
             0xbf => {                                   self.cp_a();        4 }
-            0xc0 => {                                   self.ret_nz();      8 }
+            0xc0 => {                                   if self.ret_nz() { 20 } else { 8 } }
             0xc1 => {                                   self.pop_bc();      12 }
-            0xc2 => { let v = self.getshort();          self.jp_nz_nn(v);   12 }
-            0xc3 => { let v = self.getshort();          self.jp_nn(v);      12 }
-            0xc4 => { let v = self.getshort();          self.call_nz_nn(v); 12 }
+            0xc2 => { let v = self.getshort();          if self.jp_nz_nn(v) { 16 } else { 12 } }
+            0xc3 => { let v = self.getshort();          self.jp_nn(v);      16 }
+            0xc4 => { let v = self.getshort();          if self.call_nz_nn(v) { 24 } else { 12 } }
             0xc5 => {                                   self.push_bc();     16 }
             0xc6 => { let v = self.getbyte();           self.add_a_n(v);    8 }
             0xc7 => {                                   self.rst_0();       32 }
-            0xc8 => {                                   self.ret_z();       8 }
-            0xc9 => {                                   self.ret();         8 }
-            0xca => { let v = self.getshort();          self.jp_z_nn(v);    12 }
+            0xc8 => {                                   if self.ret_z() { 20 } else { 8 } }
+            0xc9 => {                                   self.ret();         16 }
+            0xca => { let v = self.getshort();          if self.jp_z_nn(v) { 16 } else { 12 } }
             0xcb => {                           let r = self.execute_cb();  r }
-            0xcc => { let v = self.getshort();          self.call_z_nn(v);  12 }
-            0xcd => { let v = self.getshort();          self.call_nn(v);    12 }
+            0xcc => { let v = self.getshort();          if self.call_z_nn(v) { 24 } else { 12 } }
+            0xcd => { let v = self.getshort();          self.call_nn(v);    24 }
             0xce => { let v = self.getbyte();           self.adc_a_n(v);    8 }
             0xcf => {                                   self.rst_8();       32 }
-            0xd0 => {                                   self.ret_nc();      8 }
+            0xd0 => {                                   if self.ret_nc() { 20 } else { 8 } }
             0xd1 => {                                   self.pop_de();      12 }
-            0xd2 => { let v = self.getshort();          self.jp_nc_nn(v);   12 }
-            0xd4 => { let v = self.getshort();          self.call_nc_nn(v); 12 }
+            0xd2 => { let v = self.getshort();          if self.jp_nc_nn(v) { 16 } else { 12 } }
+            0xd4 => { let v = self.getshort();          if self.call_nc_nn(v) { 24 } else { 12 } }
             0xd5 => {                                   self.push_de();     16 }
             0xd6 => { let v = self.getbyte();           self.sub_a_n(v);    8 }
             0xd7 => {                                   self.rst_10();      32 }
-            0xd8 => {                                   self.ret_c();       8 }
+            0xd8 => {                                   if self.ret_c() { 20 } else { 8 } }
             0xd9 => {                                   self.reti();        8 }
-            0xda => { let v = self.getshort();          self.jp_c_nn(v);    12 }
-            0xdc => { let v = self.getshort();          self.call_c_nn(v);  12 }
+            0xda => { let v = self.getshort();          if self.jp_c_nn(v) { 16 } else { 12 } }
+            0xdc => { let v = self.getshort();          if self.call_c_nn(v) { 24 } else { 12 } }
             0xde => { let v = self.getbyte();           self.sbc_a_n(v);    8 }
             0xdf => {                                   self.rst_18();      32 }
             0xe0 => { let v = self.getbyte();           self.ldh_n_a(v);    12 }
@@ -432,6 +847,7 @@ impl CPU {
     }
 
     //0xcb
+    #[allow(clippy::assign_op_pattern)]
     fn execute_cb(&mut self) -> u16 {
         let op = self.getbyte();
         //println!("{:X}", op);
@@ -506,7 +922,7 @@ impl CPU {
             0x43 => { let v = self.register.E; self.bit(1 << 0, v); 8 }
             0x44 => { let v = self.register.H; self.bit(1 << 0, v); 8 }
             0x45 => { let v = self.register.L; self.bit(1 << 0, v); 8 }
-            0x46 => { let v = self.memory.read_byte(self.register.get_hl()); self.bit(1 << 0, v); 16 }
+            0x46 => { let v = self.read_byte(self.register.get_hl()); self.bit(1 << 0, v); 16 }
             0x47 => { let v = self.register.A; self.bit(1 << 0, v); 8 }
             0x48 => { let v = self.register.B; self.bit(1 << 1, v); 8 }
             0x49 => { let v = self.register.C; self.bit(1 << 1, v); 8 }
@@ -514,7 +930,7 @@ impl CPU {
             0x4b => { let v = self.register.E; self.bit(1 << 1, v); 8 }
             0x4c => { let v = self.register.H; self.bit(1 << 1, v); 8 }
             0x4d => { let v = self.register.L; self.bit(1 << 1, v); 8 }
-            0x4e => { let v = self.memory.read_byte(self.register.get_hl()); self.bit(1 << 1, v); 16 }
+            0x4e => { let v = self.read_byte(self.register.get_hl()); self.bit(1 << 1, v); 16 }
             0x4f => { let v = self.register.A; self.bit(1 << 1, v); 8 }
             0x50 => { let v = self.register.B; self.bit(1 << 2, v); 8 }
             0x51 => { let v = self.register.C; self.bit(1 << 2, v); 8 }
@@ -522,7 +938,7 @@ impl CPU {
             0x53 => { let v = self.register.E; self.bit(1 << 2, v); 8 }
             0x54 => { let v = self.register.H; self.bit(1 << 2, v); 8 }
             0x55 => { let v = self.register.L; self.bit(1 << 2, v); 8 }
-            0x56 => { let v = self.memory.read_byte(self.register.get_hl()); self.bit(1 << 2, v); 16 }
+            0x56 => { let v = self.read_byte(self.register.get_hl()); self.bit(1 << 2, v); 16 }
             0x57 => { let v = self.register.A; self.bit(1 << 2, v); 8 }
             0x58 => { let v = self.register.B; self.bit(1 << 3, v); 8 }
             0x59 => { let v = self.register.C; self.bit(1 << 3, v); 8 }
@@ -530,7 +946,7 @@ impl CPU {
             0x5b => { let v = self.register.E; self.bit(1 << 3, v); 8 }
             0x5c => { let v = self.register.H; self.bit(1 << 3, v); 8 }
             0x5d => { let v = self.register.L; self.bit(1 << 3, v); 8 }
-            0x5e => { let v = self.memory.read_byte(self.register.get_hl()); self.bit(1 << 3, v); 16 }
+            0x5e => { let v = self.read_byte(self.register.get_hl()); self.bit(1 << 3, v); 16 }
             0x5f => { let v = self.register.A; self.bit(1 << 3, v); 8 }
             0x60 => { let v = self.register.B; self.bit(1 << 4, v); 8 }
             0x61 => { let v = self.register.C; self.bit(1 << 4, v); 8 }
@@ -538,7 +954,7 @@ impl CPU {
             0x63 => { let v = self.register.E; self.bit(1 << 4, v); 8 }
             0x64 => { let v = self.register.H; self.bit(1 << 4, v); 8 }
             0x65 => { let v = self.register.L; self.bit(1 << 4, v); 8 }
-            0x66 => { let v = self.memory.read_byte(self.register.get_hl()); self.bit(1 << 4, v); 16 }
+            0x66 => { let v = self.read_byte(self.register.get_hl()); self.bit(1 << 4, v); 16 }
             0x67 => { let v = self.register.A; self.bit(1 << 4, v); 8 }
             0x68 => { let v = self.register.B; self.bit(1 << 5, v); 8 }
             0x69 => { let v = self.register.C; self.bit(1 << 5, v); 8 }
@@ -546,7 +962,7 @@ impl CPU {
             0x6b => { let v = self.register.E; self.bit(1 << 5, v); 8 }
             0x6c => { let v = self.register.H; self.bit(1 << 5, v); 8 }
             0x6d => { let v = self.register.L; self.bit(1 << 5, v); 8 }
-            0x6e => { let v = self.memory.read_byte(self.register.get_hl()); self.bit(1 << 5, v); 16 }
+            0x6e => { let v = self.read_byte(self.register.get_hl()); self.bit(1 << 5, v); 16 }
             0x6f => { let v = self.register.A; self.bit(1 << 5, v); 8 }
             0x70 => { let v = self.register.B; self.bit(1 << 6, v); 8 }
             0x71 => { let v = self.register.C; self.bit(1 << 6, v); 8 }
@@ -554,7 +970,7 @@ impl CPU {
             0x73 => { let v = self.register.E; self.bit(1 << 6, v); 8 }
             0x74 => { let v = self.register.H; self.bit(1 << 6, v); 8 }
             0x75 => { let v = self.register.L; self.bit(1 << 6, v); 8 }
-            0x76 => { let v = self.memory.read_byte(self.register.get_hl()); self.bit(1 << 6, v); 16 }
+            0x76 => { let v = self.read_byte(self.register.get_hl()); self.bit(1 << 6, v); 16 }
             0x77 => { let v = self.register.A; self.bit(1 << 6, v); 8 }
             0x78 => { let v = self.register.B; self.bit(1 << 7, v); 8 }
             0x79 => { let v = self.register.C; self.bit(1 << 7, v); 8 }
@@ -562,7 +978,7 @@ impl CPU {
             0x7b => { let v = self.register.E; self.bit(1 << 7, v); 8 }
             0x7c => { let v = self.register.H; self.bit(1 << 7, v); 8 }
             0x7d => { let v = self.register.L; self.bit(1 << 7, v); 8 }
-            0x7e => { let v = self.memory.read_byte(self.register.get_hl()); self.bit(1 << 7, v); 16 }
+            0x7e => { let v = self.read_byte(self.register.get_hl()); self.bit(1 << 7, v); 16 }
             0x7f => { let v = self.register.A; self.bit(1 << 7, v); 8 }
             0x80 => { self.register.B = self.register.B & !(1 << 0); 8 }
             0x81 => { self.register.C = self.register.C & !(1 << 0); 8 }
@@ -570,8 +986,8 @@ impl CPU {
             0x83 => { self.register.E = self.register.E & !(1 << 0); 8 }
             0x84 => { self.register.H = self.register.H & !(1 << 0); 8 }
             0x85 => { self.register.L = self.register.L & !(1 << 0); 8 }
-            0x86 => { let v = self.memory.read_byte(self.register.get_hl()) & !(1 << 0);
-                      self.memory.write_byte(self.register.get_hl(), v); 16 }
+            0x86 => { let v = self.read_byte(self.register.get_hl()) & !(1 << 0);
+                      self.write_byte(self.register.get_hl(), v); 16 }
             0x87 => { self.register.A = self.register.A & !(1 << 0); 8 }
             0x88 => { self.register.B = self.register.B & !(1 << 1); 8 }
             0x89 => { self.register.C = self.register.C & !(1 << 1); 8 }
@@ -579,8 +995,8 @@ impl CPU {
             0x8b => { self.register.E = self.register.E & !(1 << 1); 8 }
             0x8c => { self.register.H = self.register.H & !(1 << 1); 8 }
             0x8d => { self.register.L = self.register.L & !(1 << 1); 8 }
-            0x8e => { let v = self.memory.read_byte(self.register.get_hl()) & !(1 << 1);
-                      self.memory.write_byte(self.register.get_hl(), v); 16 }
+            0x8e => { let v = self.read_byte(self.register.get_hl()) & !(1 << 1);
+                      self.write_byte(self.register.get_hl(), v); 16 }
             0x8f => { self.register.A = self.register.A & !(1 << 1); 8 }
             0x90 => { self.register.B = self.register.B & !(1 << 2); 8 }
             0x91 => { self.register.C = self.register.C & !(1 << 2); 8 }
@@ -588,8 +1004,8 @@ impl CPU {
             0x93 => { self.register.E = self.register.E & !(1 << 2); 8 }
             0x94 => { self.register.H = self.register.H & !(1 << 2); 8 }
             0x95 => { self.register.L = self.register.L & !(1 << 2); 8 }
-            0x96 => { let v = self.memory.read_byte(self.register.get_hl()) & !(1 << 2);
-                      self.memory.write_byte(self.register.get_hl(), v); 16 }
+            0x96 => { let v = self.read_byte(self.register.get_hl()) & !(1 << 2);
+                      self.write_byte(self.register.get_hl(), v); 16 }
             0x97 => { self.register.A = self.register.A & !(1 << 2); 8 }
             0x98 => { self.register.B = self.register.B & !(1 << 3); 8 }
             0x99 => { self.register.C = self.register.C & !(1 << 3); 8 }
@@ -597,8 +1013,8 @@ impl CPU {
             0x9b => { self.register.E = self.register.E & !(1 << 3); 8 }
             0x9c => { self.register.H = self.register.H & !(1 << 3); 8 }
             0x9d => { self.register.L = self.register.L & !(1 << 3); 8 }
-            0x9e => { let v = self.memory.read_byte(self.register.get_hl()) & !(1 << 3);
-                      self.memory.write_byte(self.register.get_hl(), v); 16 }
+            0x9e => { let v = self.read_byte(self.register.get_hl()) & !(1 << 3);
+                      self.write_byte(self.register.get_hl(), v); 16 }
             0x9f => { self.register.A = self.register.A & !(1 << 3); 8 }
             0xa0 => { self.register.B = self.register.B & !(1 << 4); 8 }
             0xa1 => { self.register.C = self.register.C & !(1 << 4); 8 }
@@ -606,8 +1022,8 @@ impl CPU {
             0xa3 => { self.register.E = self.register.E & !(1 << 4); 8 }
             0xa4 => { self.register.H = self.register.H & !(1 << 4); 8 }
             0xa5 => { self.register.L = self.register.L & !(1 << 4); 8 }
-            0xa6 => { let v = self.memory.read_byte(self.register.get_hl()) & !(1 << 4);
-                      self.memory.write_byte(self.register.get_hl(), v); 16 }
+            0xa6 => { let v = self.read_byte(self.register.get_hl()) & !(1 << 4);
+                      self.write_byte(self.register.get_hl(), v); 16 }
             0xa7 => { self.register.A = self.register.A & !(1 << 4); 8 }
             0xa8 => { self.register.B = self.register.B & !(1 << 5); 8 }
             0xa9 => { self.register.C = self.register.C & !(1 << 5); 8 }
@@ -615,8 +1031,8 @@ impl CPU {
             0xab => { self.register.E = self.register.E & !(1 << 5); 8 }
             0xac => { self.register.H = self.register.H & !(1 << 5); 8 }
             0xad => { self.register.L = self.register.L & !(1 << 5); 8 }
-            0xae => { let v = self.memory.read_byte(self.register.get_hl()) & !(1 << 5);
-                      self.memory.write_byte(self.register.get_hl(), v); 16 }
+            0xae => { let v = self.read_byte(self.register.get_hl()) & !(1 << 5);
+                      self.write_byte(self.register.get_hl(), v); 16 }
             0xaf => { self.register.A = self.register.A & !(1 << 5); 8 }
             0xb0 => { self.register.B = self.register.B & !(1 << 6); 8 }
             0xb1 => { self.register.C = self.register.C & !(1 << 6); 8 }
@@ -624,8 +1040,8 @@ impl CPU {
             0xb3 => { self.register.E = self.register.E & !(1 << 6); 8 }
             0xb4 => { self.register.H = self.register.H & !(1 << 6); 8 }
             0xb5 => { self.register.L = self.register.L & !(1 << 6); 8 }
-            0xb6 => { let v = self.memory.read_byte(self.register.get_hl()) & !(1 << 6);
-                      self.memory.write_byte(self.register.get_hl(), v); 16 }
+            0xb6 => { let v = self.read_byte(self.register.get_hl()) & !(1 << 6);
+                      self.write_byte(self.register.get_hl(), v); 16 }
             0xb7 => { self.register.A = self.register.A & !(1 << 6); 8 }
             0xb8 => { self.register.B = self.register.B & !(1 << 7); 8 }
             0xb9 => { self.register.C = self.register.C & !(1 << 7); 8 }
@@ -633,8 +1049,8 @@ impl CPU {
             0xbb => { self.register.E = self.register.E & !(1 << 7); 8 }
             0xbc => { self.register.H = self.register.H & !(1 << 7); 8 }
             0xbd => { self.register.L = self.register.L & !(1 << 7); 8 }
-            0xbe => { let v = self.memory.read_byte(self.register.get_hl()) & !(1 << 7);
-                      self.memory.write_byte(self.register.get_hl(), v); 16 }
+            0xbe => { let v = self.read_byte(self.register.get_hl()) & !(1 << 7);
+                      self.write_byte(self.register.get_hl(), v); 16 }
             0xbf => { self.register.A = self.register.A & !(1 << 7); 8 }
             0xc0 => { self.register.B = self.register.B | (1 << 0); 8 }
             0xc1 => { self.register.C = self.register.C | (1 << 0); 8 }
@@ -642,8 +1058,8 @@ impl CPU {
             0xc3 => { self.register.E = self.register.E | (1 << 0); 8 }
             0xc4 => { self.register.H = self.register.H | (1 << 0); 8 }
             0xc5 => { self.register.L = self.register.L | (1 << 0); 8 }
-            0xc6 => { let v = self.memory.read_byte(self.register.get_hl()) | (1 << 0);
-                      self.memory.write_byte(self.register.get_hl(), v); 16 }
+            0xc6 => { let v = self.read_byte(self.register.get_hl()) | (1 << 0);
+                      self.write_byte(self.register.get_hl(), v); 16 }
             0xc7 => { self.register.A = self.register.A | (1 << 0); 8 }
             0xc8 => { self.register.B = self.register.B | (1 << 1); 8 }
             0xc9 => { self.register.C = self.register.C | (1 << 1); 8 }
@@ -651,8 +1067,8 @@ impl CPU {
             0xcb => { self.register.E = self.register.E | (1 << 1); 8 }
             0xcc => { self.register.H = self.register.H | (1 << 1); 8 }
             0xcd => { self.register.L = self.register.L | (1 << 1); 8 }
-            0xce => { let v = self.memory.read_byte(self.register.get_hl()) | (1 << 1);
-                      self.memory.write_byte(self.register.get_hl(), v); 16 }
+            0xce => { let v = self.read_byte(self.register.get_hl()) | (1 << 1);
+                      self.write_byte(self.register.get_hl(), v); 16 }
             0xcf => { self.register.A = self.register.A | (1 << 1); 8 }
             0xd0 => { self.register.B = self.register.B | (1 << 2); 8 }
             0xd1 => { self.register.C = self.register.C | (1 << 2); 8 }
@@ -660,8 +1076,8 @@ impl CPU {
             0xd3 => { self.register.E = self.register.E | (1 << 2); 8 }
             0xd4 => { self.register.H = self.register.H | (1 << 2); 8 }
             0xd5 => { self.register.L = self.register.L | (1 << 2); 8 }
-            0xd6 => { let v = self.memory.read_byte(self.register.get_hl()) | (1 << 2);
-                      self.memory.write_byte(self.register.get_hl(), v); 16 }
+            0xd6 => { let v = self.read_byte(self.register.get_hl()) | (1 << 2);
+                      self.write_byte(self.register.get_hl(), v); 16 }
             0xd7 => { self.register.A = self.register.A | (1 << 2); 8 }
             0xd8 => { self.register.B = self.register.B | (1 << 3); 8 }
             0xd9 => { self.register.C = self.register.C | (1 << 3); 8 }
@@ -669,8 +1085,8 @@ impl CPU {
             0xdb => { self.register.E = self.register.E | (1 << 3); 8 }
             0xdc => { self.register.H = self.register.H | (1 << 3); 8 }
             0xdd => { self.register.L = self.register.L | (1 << 3); 8 }
-            0xde => { let v = self.memory.read_byte(self.register.get_hl()) | (1 << 3);
-                      self.memory.write_byte(self.register.get_hl(), v); 16 }
+            0xde => { let v = self.read_byte(self.register.get_hl()) | (1 << 3);
+                      self.write_byte(self.register.get_hl(), v); 16 }
             0xdf => { self.register.A = self.register.A | (1 << 3); 8 }
             0xe0 => { self.register.B = self.register.B | (1 << 4); 8 }
             0xe1 => { self.register.C = self.register.C | (1 << 4); 8 }
@@ -678,8 +1094,8 @@ impl CPU {
             0xe3 => { self.register.E = self.register.E | (1 << 4); 8 }
             0xe4 => { self.register.H = self.register.H | (1 << 4); 8 }
             0xe5 => { self.register.L = self.register.L | (1 << 4); 8 }
-            0xe6 => { let v = self.memory.read_byte(self.register.get_hl()) | (1 << 4);
-                      self.memory.write_byte(self.register.get_hl(), v); 16 }
+            0xe6 => { let v = self.read_byte(self.register.get_hl()) | (1 << 4);
+                      self.write_byte(self.register.get_hl(), v); 16 }
             0xe7 => { self.register.A = self.register.A | (1 << 4); 8 }
             0xe8 => { self.register.B = self.register.B | (1 << 5); 8 }
             0xe9 => { self.register.C = self.register.C | (1 << 5); 8 }
@@ -687,8 +1103,8 @@ impl CPU {
             0xeb => { self.register.E = self.register.E | (1 << 5); 8 }
             0xec => { self.register.H = self.register.H | (1 << 5); 8 }
             0xed => { self.register.L = self.register.L | (1 << 5); 8 }
-            0xee => { let v = self.memory.read_byte(self.register.get_hl()) | (1 << 5);
-                      self.memory.write_byte(self.register.get_hl(), v); 16 }
+            0xee => { let v = self.read_byte(self.register.get_hl()) | (1 << 5);
+                      self.write_byte(self.register.get_hl(), v); 16 }
             0xef => { self.register.A = self.register.A | (1 << 5); 8 }
             0xf0 => { self.register.B = self.register.B | (1 << 6); 8 }
             0xf1 => { self.register.C = self.register.C | (1 << 6); 8 }
@@ -696,8 +1112,8 @@ impl CPU {
             0xf3 => { self.register.E = self.register.E | (1 << 6); 8 }
             0xf4 => { self.register.H = self.register.H | (1 << 6); 8 }
             0xf5 => { self.register.L = self.register.L | (1 << 6); 8 }
-            0xf6 => { let v = self.memory.read_byte(self.register.get_hl()) | (1 << 6);
-                      self.memory.write_byte(self.register.get_hl(), v); 16 }
+            0xf6 => { let v = self.read_byte(self.register.get_hl()) | (1 << 6);
+                      self.write_byte(self.register.get_hl(), v); 16 }
             0xf7 => { self.register.A = self.register.A | (1 << 6); 8 }
             0xf8 => { self.register.B = self.register.B | (1 << 7); 8 }
             0xf9 => { self.register.C = self.register.C | (1 << 7); 8 }
@@ -705,10 +1121,9 @@ impl CPU {
             0xfb => { self.register.E = self.register.E | (1 << 7); 8 }
             0xfc => { self.register.H = self.register.H | (1 << 7); 8 }
             0xfd => { self.register.L = self.register.L | (1 << 7); 8 }
-            0xfe => { let v = self.memory.read_byte(self.register.get_hl()) | (1 << 7);
-                      self.memory.write_byte(self.register.get_hl(), v); 16 }
+            0xfe => { let v = self.read_byte(self.register.get_hl()) | (1 << 7);
+                      self.write_byte(self.register.get_hl(), v); 16 }
             0xff => { self.register.A = self.register.A | (1 << 7); 8 }
-            _ => panic!("Unknown instruction in cb")
         }
     }
 
@@ -718,7 +1133,7 @@ impl CPU {
 
     fn rlc(&mut self, value: u8) -> u8 {
         let carry = value & 0x80 == 0x80;
-        let v = (value << 1) + (if carry { 1 } else { 0 });
+        let v = (value << 1) | (if carry { 1 } else { 0 });
         if carry { self.register.flag_set(C) } else { self.register.flag_reset(C) }
         if v == 0 { self.register.flag_set(Z) } else { self.register.flag_reset(Z) }
         self.register.flag_reset(N);
@@ -737,11 +1152,7 @@ impl CPU {
     }
 
     fn rl(&mut self, value: u8) -> u8 {
-        // let carry = value & 0x80 == 0x80;
-        // let v = (value << 1) | (if self.register.flag_get(C) { 1 } else { 0 });
-        // if carry { self.register.flag_set(C) } else { self.register.flag_reset(C) }
-        let carry = (if self.register.flag_get(C) { 1 } else { 0 });
-        let v = (value << 1) + carry;
+        let v = (value << 1) | (if self.register.flag_get(C) { 1 } else { 0 });
         if value & 0x80 != 0 { self.register.flag_set(C) } else { self.register.flag_reset(C) }
         if v == 0 { self.register.flag_set(Z) } else { self.register.flag_reset(Z) }
         self.register.flag_reset(N);
@@ -750,9 +1161,6 @@ impl CPU {
     }
 
     fn rr(&mut self, value: u8) -> u8 {
-        // let carry = value & 0x01 == 0x01;
-        // let v = (value >> 1) | (if self.register.flag_get(C) { 0x80 } else { 0 });
-        // if carry { self.register.flag_set(C) } else { self.register.flag_reset(C) }
         let v = (value >> 1) | (if self.register.flag_get(C) { 0x80 } else { 0 });
         if (value & 0x01) != 0 { self.register.flag_set(C) } else { self.register.flag_reset(C) }
         if v == 0 { self.register.flag_set(Z) } else { self.register.flag_reset(Z) }
@@ -842,9 +1250,9 @@ impl CPU {
 
     //0x06
     fn rlc_hl(&mut self) {
-        let v = self.memory.read_byte(self.register.get_hl());
+        let v = self.read_byte(self.register.get_hl());
         let v2 = self.rlc(v);
-        self.memory.write_byte(self.register.get_hl(), v2);
+        self.write_byte(self.register.get_hl(), v2);
     }
 
     //0x07
@@ -891,9 +1299,9 @@ impl CPU {
 
     //0x0e
     fn rrc_hl(&mut self) {
-        let v = self.memory.read_byte(self.register.get_hl());
+        let v = self.read_byte(self.register.get_hl());
         let v2 = self.rrc(v);
-        self.memory.write_byte(self.register.get_hl(), v2);
+        self.write_byte(self.register.get_hl(), v2);
     }
 
     //0x0f
@@ -940,9 +1348,9 @@ impl CPU {
 
     //0x16
     fn rl_hl(&mut self) {
-        let v = self.memory.read_byte(self.register.get_hl());
+        let v = self.read_byte(self.register.get_hl());
         let v2 = self.rl(v);
-        self.memory.write_byte(self.register.get_hl(), v2);
+        self.write_byte(self.register.get_hl(), v2);
     }
 
     //0x17
@@ -989,9 +1397,9 @@ impl CPU {
 
     //0x1e
     fn rr_hl(&mut self) {
-        let v = self.memory.read_byte(self.register.get_hl());
+        let v = self.read_byte(self.register.get_hl());
         let v2 = self.rr(v);
-        self.memory.write_byte(self.register.get_hl(), v2);
+        self.write_byte(self.register.get_hl(), v2);
     }
 
     //0x1f
@@ -1038,9 +1446,9 @@ impl CPU {
 
     //0x26
     fn sla_hl(&mut self) {
-        let v = self.memory.read_byte(self.register.get_hl());
+        let v = self.read_byte(self.register.get_hl());
         let v2 = self.sla(v);
-        self.memory.write_byte(self.register.get_hl(), v2);
+        self.write_byte(self.register.get_hl(), v2);
     }
 
     //0x27
@@ -1087,9 +1495,9 @@ impl CPU {
 
     //0x2e
     fn sra_hl(&mut self) {
-        let v = self.memory.read_byte(self.register.get_hl());
+        let v = self.read_byte(self.register.get_hl());
         let v2 = self.sra(v);
-        self.memory.write_byte(self.register.get_hl(), v2);
+        self.write_byte(self.register.get_hl(), v2);
     }
 
     //0x2f
@@ -1136,9 +1544,9 @@ impl CPU {
 
     //0x36
     fn swap_hl(&mut self) {
-        let v = self.memory.read_byte(self.register.get_hl());
+        let v = self.read_byte(self.register.get_hl());
         let v2 = self.swap(v);
-        self.memory.write_byte(self.register.get_hl(), v2);
+        self.write_byte(self.register.get_hl(), v2);
     }
 
     //0x37
@@ -1185,9 +1593,9 @@ impl CPU {
 
     //0x3e
     fn srl_hl(&mut self) {
-        let v = self.memory.read_byte(self.register.get_hl());
+        let v = self.read_byte(self.register.get_hl());
         let v2 = self.srl(v);
-        self.memory.write_byte(self.register.get_hl(), v2);
+        self.write_byte(self.register.get_hl(), v2);
     }
 
     //0x3f
@@ -1243,69 +1651,61 @@ impl CPU {
         self.register.A = v;
     }
 
+    // Shared by SUB and CP, sets the flags for A - value and returns the result
+    fn compare(&mut self, value: u8) -> u8 {
+        let a = self.register.A;
+        let v = a.wrapping_sub(value);
+        self.register.set_flags_znhc(Some(v == 0), Some(true), Some((a & 0x0f) < (value & 0x0f)), Some(value > a));
+        v
+    }
+
     fn cp(&mut self, value: u8) {
-        if self.register.A == value { self.register.flag_set(Z) } else { self.register.flag_reset(Z) }
-        if (self.register.A & 0x0f) < (value & 0x0f) { self.register.flag_set(H) } else { self.register.flag_reset(H) }
-        if self.register.A < value { self.register.flag_set(C) } else { self.register.flag_reset(C) }
-        self.register.flag_set(N);
+        self.compare(value);
     }
 
     fn add_a(&mut self, value: u8) {
         let a = self.register.A;
-        let v = a.wrapping_add(value);
-        if v == 0 { self.register.flag_set(Z) } else { self.register.flag_reset(Z) }
-        if (self.register.A & 0x0f) + (value & 0x0f) > 0x0f { self.register.flag_set(H) } else { self.register.flag_reset(H) }
-        //if (self.register.A as u16 + value as u16) > 0xff { self.register.flag_set(C) } else { self.register.flag_reset(C) }
-        if (v & 0xff00) != 0 { self.register.flag_set(C) } else { self.register.flag_reset(C) }
-        self.register.flag_reset(N);
+        let sum = a as u16 + value as u16;
+        let v = sum as u8;
+        self.register.set_flags_znhc(Some(v == 0), Some(false), Some((a & 0x0f) + (value & 0x0f) > 0x0f), Some(sum > 0xff));
         self.register.A = v;
     }
 
     fn adc_a(&mut self, value: u8) {
         let a = self.register.A;
         let carry = if self.register.flag_get(C) { 1 } else { 0 };
-        let v = a.wrapping_add(value).wrapping_add(carry);
-        //if v == 0 { self.register.flag_set(Z) } else { self.register.flag_reset(Z) }
-        if a == value { self.register.flag_set(Z) } else { self.register.flag_reset(Z) }
-        if (self.register.A & 0x0f) + (value & 0x0f) > 0x0f { self.register.flag_set(H) } else { self.register.flag_reset(H) }
-        //if (self.register.A as u16 + value as u16 + carry as u16) > 0xff { self.register.flag_set(C) } else { self.register.flag_reset(C) }
-        if (v & 0xff00) != 0 { self.register.flag_set(C) } else { self.register.flag_reset(C) }
-        self.register.flag_reset(N); // CHECK
-        //self.register.A = v;
-        self.register.A = v & 0xff;
+        let sum = a as u16 + value as u16 + carry as u16;
+        let v = sum as u8;
+        if v == 0 { self.register.flag_set(Z) } else { self.register.flag_reset(Z) }
+        if (self.register.A & 0x0f) + (value & 0x0f) + carry > 0x0f { self.register.flag_set(H) } else { self.register.flag_reset(H) }
+        if sum > 0xff { self.register.flag_set(C) } else { self.register.flag_reset(C) }
+        self.register.flag_reset(N);
+        self.register.A = v;
     }
 
     fn sub_a(&mut self, value: u8) {
-        let a = self.register.A;
-        let v = a.wrapping_sub(value);
-        if v == 0 { self.register.flag_set(Z) } else { self.register.flag_reset(Z) }
-        if (self.register.A & 0x0f) < (value & 0x0f) { self.register.flag_set(H) } else { self.register.flag_reset(H) }
-        if value > self.register.A { self.register.flag_set(C) } else { self.register.flag_reset(C) }
-        self.register.flag_set(N);
-        self.register.A = v;
+        self.register.A = self.compare(value);
     }
 
     fn sbc_a(&mut self, value: u8) {
         let a = self.register.A;
         let carry = if self.register.flag_get(C) { 1 } else { 0 };
         let v = a.wrapping_sub(value).wrapping_sub(carry);
-        //if v == 0 { self.register.flag_set(Z) } else { self.register.flag_reset(Z) }
-        if v == a { self.register.flag_set(Z) } else { self.register.flag_reset(Z) }
-        if (self.register.A & 0x0f) < ((value + carry) & 0x0f) { self.register.flag_set(H) } else { self.register.flag_reset(H) }
-        if value > self.register.A { self.register.flag_set(C) } else { self.register.flag_reset(C) }
+        if v == 0 { self.register.flag_set(Z) } else { self.register.flag_reset(Z) }
+        if (a & 0x0f) < (value & 0x0f) + carry { self.register.flag_set(H) } else { self.register.flag_reset(H) }
+        if (a as u16) < value as u16 + carry as u16 { self.register.flag_set(C) } else { self.register.flag_reset(C) }
         self.register.flag_set(N);
         self.register.A = v;
     }
 
     fn add_hl(&mut self, value: u16) {
         let hl = self.register.get_hl();
-        let res = hl.wrapping_add(value);
+        let sum = hl as u32 + value as u32;
         self.register.flag_reset(N);
-        //if hl > 0xFFFF - value { self.register.flag_set(C) } else { self.register.flag_reset(C) }
-        if (res & 0xffff0000) != 0 { self.register.flag_set(C) } else { self.register.flag_reset(C) }
-        //if (hl & 0x07FF) + (value & 0x07FF) > 0x07FF { self.register.flag_set(H) } else { self.register.flag_reset(H) }
-        if (hl & 0x0f) + (value & 0x0f) > 0x0f { self.register.flag_set(H) } else { self.register.flag_reset(H) }
-        self.register.set_hl(res);
+        // Carry from bit 15, half carry from bit 11. Z is left untouched
+        if sum > 0xffff { self.register.flag_set(C) } else { self.register.flag_reset(C) }
+        if (hl & 0x0fff) + (value & 0x0fff) > 0x0fff { self.register.flag_set(H) } else { self.register.flag_reset(H) }
+        self.register.set_hl(sum as u16);
     }
 
     // http://imrannazar.com/Gameboy-Z80-Opcode-Map
@@ -1323,7 +1723,7 @@ impl CPU {
 
     //0x02
     fn ld_bc_a(&mut self) {
-        self.memory.write_byte(self.register.get_bc(), self.register.A);
+        self.write_byte(self.register.get_bc(), self.register.A);
     }
 
     //0x03
@@ -1364,7 +1764,7 @@ impl CPU {
 
     //0x08
     fn ld_nn_sp(&mut self, operand: u16) {
-        self.memory.write_short(operand, self.register.SP);
+        self.write_short(operand, self.register.SP);
     }
 
     //0x09
@@ -1374,7 +1774,7 @@ impl CPU {
 
     //0x0a
     fn ld_a_bc(&mut self) {
-        let v = self.memory.read_byte(self.register.get_bc());
+        let v = self.read_byte(self.register.get_bc());
         self.register.A = v;
     }
 
@@ -1416,6 +1816,20 @@ impl CPU {
 
     //0x10
     fn stop(&mut self) {
+        // STOP is encoded as 0x10 0x00. Anything but 0x00 after it glitches on
+        // hardware, the byte is skipped either way so execution stays aligned.
+        // Skipping it takes no bus cycle of its own, STOP is a single M-cycle
+        let operand = self.memory.read_byte(self.register.PC);
+        self.register.PC = self.register.PC.wrapping_add(1);
+        if operand != 0 && self.debugging {
+            println!("Warning: STOP followed by {:02X} at {:04X}", operand, self.register.PC.wrapping_sub(2));
+        }
+        // A prepared CGB speed switch consumes the STOP instead of entering low power
+        if self.memory.switch_speed() { return; }
+        // Entering STOP also resets DIV, through Memory so TIMA sees the edge.
+        // Not a bus access, so no cycle is charged for it
+        self.memory.write_byte(0xff04, 0);
+        // Low power until a joypad line goes low, see cpu_cycle
         self.stopped = true;
     }
 
@@ -1426,7 +1840,7 @@ impl CPU {
 
     //0x12
     fn ld_de_a(&mut self) {
-        self.memory.write_byte(self.register.get_de(), self.register.A);
+        self.write_byte(self.register.get_de(), self.register.A);
     }
 
     //0x13
@@ -1477,7 +1891,7 @@ impl CPU {
 
     //0x1a
     fn ld_a_de(&mut self) {
-        let v = self.memory.read_byte(self.register.get_de());
+        let v = self.read_byte(self.register.get_de());
         self.register.A = v;
     }
 
@@ -1518,10 +1932,12 @@ impl CPU {
     }
 
     //0x20
-    fn jr_nz_n(&mut self, operand: i8) {
-        if !self.register.flag_get(Z) {
+    fn jr_nz_n(&mut self, operand: i8) -> bool {
+        let taken = !self.register.flag_get(Z);
+        if taken {
             self.register.PC = ((self.register.PC as u32 as i32) + operand as i32) as u16;
         }
+        taken
     }
 
     //0x21
@@ -1531,7 +1947,7 @@ impl CPU {
 
     //0x22
     fn ldi_hl_a(&mut self) {
-        self.memory.write_byte(self.register.get_hl(), self.register.A);
+        self.write_byte(self.register.get_hl(), self.register.A);
         let v = self.register.get_hl().wrapping_add(1);
         self.register.set_hl(v);
     }
@@ -1580,10 +1996,12 @@ impl CPU {
     }
 
     //0x28
-    fn jr_z_n(&mut self, operand: i8) {
-        if self.register.flag_get(Z) {
+    fn jr_z_n(&mut self, operand: i8) -> bool {
+        let taken = self.register.flag_get(Z);
+        if taken {
             self.register.PC = ((self.register.PC as u32 as i32) + operand as i32) as u16;
         }
+        taken
     }
 
     //0x29
@@ -1593,7 +2011,7 @@ impl CPU {
 
     //0x2a
     fn ldi_a_hl(&mut self) {
-        let a = self.memory.read_byte(self.register.get_hl());
+        let a = self.read_byte(self.register.get_hl());
         let v = self.register.get_hl().wrapping_add(1);
         self.register.set_hl(v);
         self.register.A = a;
@@ -1632,10 +2050,12 @@ impl CPU {
     }
 
     //0x30 
-    fn jr_nc_n(&mut self, operand: i8) {
-        if !self.register.flag_get(C) {
+    fn jr_nc_n(&mut self, operand: i8) -> bool {
+        let taken = !self.register.flag_get(C);
+        if taken {
             self.register.PC = ((self.register.PC as u32 as i32) + operand as i32) as u16;
         }
+        taken
     }
 
     //0x31
@@ -1645,33 +2065,33 @@ impl CPU {
 
     //0x32
     fn ldd_hl_a(&mut self) {
-        self.memory.write_byte(self.register.get_hl(), self.register.A);
+        self.write_byte(self.register.get_hl(), self.register.A);
         let v = self.register.get_hl().wrapping_sub(1);
         self.register.set_hl(v);
     }
 
     //0x33
     fn inc_sp(&mut self) {
-        self.register.SP += 1;
+        self.register.SP = self.register.SP.wrapping_add(1);
     }
 
     //0x34
     fn inc_hl_ptr(&mut self) {
-        let v = self.memory.read_byte(self.register.get_hl());
+        let v = self.read_byte(self.register.get_hl());
         let new = self.inc(v);
-        self.memory.write_byte(self.register.get_hl(), new);
+        self.write_byte(self.register.get_hl(), new);
     }
 
     //0x35
     fn dec_hl_ptr(&mut self) {
-        let v = self.memory.read_byte(self.register.get_hl());
+        let v = self.read_byte(self.register.get_hl());
         let new = self.dec(v);
-        self.memory.write_byte(self.register.get_hl(), new);
+        self.write_byte(self.register.get_hl(), new);
     }
 
     //0x36
     fn ld_hl_n(&mut self, operand: u8) {
-        self.memory.write_byte(self.register.get_hl(), operand);
+        self.write_byte(self.register.get_hl(), operand);
     }
 
     //0x37
@@ -1682,10 +2102,12 @@ impl CPU {
     }
 
     //0x38
-    fn jr_c_n(&mut self, operand: i8) {
-        if self.register.flag_get(C) {
+    fn jr_c_n(&mut self, operand: i8) -> bool {
+        let taken = self.register.flag_get(C);
+        if taken {
             self.register.PC = ((self.register.PC as u32 as i32) + operand as i32) as u16;
         }
+        taken
     }
 
     //0x39
@@ -1695,7 +2117,7 @@ impl CPU {
 
     //0x3a
     fn ldd_a_hl(&mut self) {
-        self.register.A = self.memory.read_byte(self.register.get_hl());
+        self.register.A = self.read_byte(self.register.get_hl());
         let v = self.register.get_hl().wrapping_sub(1);
         self.register.set_hl(v);
     }
@@ -1731,6 +2153,11 @@ impl CPU {
         self.register.flag_reset(H);
     }
 
+    //0x40
+    fn ld_b_b(&mut self) {
+        // Loading a register into itself changes nothing
+    }
+
     //0x41
     fn ld_b_c(&mut self) {
         self.register.B = self.register.C;
@@ -1758,7 +2185,7 @@ impl CPU {
 
     //0x46
     fn ld_b_hl(&mut self) {
-        self.register.B = self.memory.read_byte(self.register.get_hl());
+        self.register.B = self.read_byte(self.register.get_hl());
     }
 
     //0x47
@@ -1771,6 +2198,11 @@ impl CPU {
         self.register.C = self.register.B;
     }
 
+    //0x49
+    fn ld_c_c(&mut self) {
+        // Loading a register into itself changes nothing
+    }
+
     //0x4a
     fn ld_c_d(&mut self) {
         self.register.C = self.register.D;
@@ -1793,7 +2225,7 @@ impl CPU {
 
     //0x4e
     fn ld_c_hl(&mut self) {
-        self.register.C = self.memory.read_byte(self.register.get_hl());
+        self.register.C = self.read_byte(self.register.get_hl());
     }
 
     //0x4f
@@ -1811,6 +2243,11 @@ impl CPU {
         self.register.D = self.register.C;
     }
 
+    //0x52
+    fn ld_d_d(&mut self) {
+        // Loading a register into itself changes nothing
+    }
+
     //0x53
     fn ld_d_e(&mut self) {
         self.register.D = self.register.E;
@@ -1828,7 +2265,7 @@ impl CPU {
 
     //0x56
     fn ld_d_hl(&mut self) {
-        self.register.D = self.memory.read_byte(self.register.get_hl());
+        self.register.D = self.read_byte(self.register.get_hl());
     }
 
     //0x57
@@ -1851,6 +2288,11 @@ impl CPU {
         self.register.E = self.register.D;
     }
 
+    //0x5b
+    fn ld_e_e(&mut self) {
+        // Loading a register into itself changes nothing
+    }
+
     //0x5c
     fn ld_e_h(&mut self) {
         self.register.E = self.register.H;
@@ -1863,7 +2305,7 @@ impl CPU {
 
     //0x5e
     fn ld_e_hl(&mut self) {
-        self.register.E = self.memory.read_byte(self.register.get_hl());
+        self.register.E = self.read_byte(self.register.get_hl());
     }
 
     //0x5f
@@ -1891,6 +2333,11 @@ impl CPU {
         self.register.H = self.register.E;
     }
 
+    //0x64
+    fn ld_h_h(&mut self) {
+        // Loading a register into itself changes nothing
+    }
+
     //0x65
     fn ld_h_l(&mut self) {
         self.register.H = self.register.L;
@@ -1898,7 +2345,7 @@ impl CPU {
 
     //0x66
     fn ld_h_hl(&mut self) {
-        self.register.H = self.memory.read_byte(self.register.get_hl());
+        self.register.H = self.read_byte(self.register.get_hl());
     }
 
     //0x67
@@ -1931,9 +2378,14 @@ impl CPU {
         self.register.L = self.register.H;
     }
 
+    //0x6d
+    fn ld_l_l(&mut self) {
+        // Loading a register into itself changes nothing
+    }
+
     //0x6e
     fn ld_l_hl(&mut self) {
-        self.register.L = self.memory.read_byte(self.register.get_hl());
+        self.register.L = self.read_byte(self.register.get_hl());
     }
 
     //0x6f
@@ -1943,42 +2395,48 @@ impl CPU {
 
     //0x70
     fn ld_hl_b(&mut self) {
-        self.memory.write_byte(self.register.get_hl(), self.register.B);
+        self.write_byte(self.register.get_hl(), self.register.B);
     }
 
     //0x71
     fn ld_hl_c(&mut self) {
-        self.memory.write_byte(self.register.get_hl(), self.register.C);
+        self.write_byte(self.register.get_hl(), self.register.C);
     }
 
     //0x72
     fn ld_hl_d(&mut self) {
-        self.memory.write_byte(self.register.get_hl(), self.register.D);
+        self.write_byte(self.register.get_hl(), self.register.D);
     }
 
     //0x73
     fn ld_hl_e(&mut self) {
-        self.memory.write_byte(self.register.get_hl(), self.register.E);
+        self.write_byte(self.register.get_hl(), self.register.E);
     }
 
     //0x74
     fn ld_hl_h(&mut self) {
-        self.memory.write_byte(self.register.get_hl(), self.register.H);
+        self.write_byte(self.register.get_hl(), self.register.H);
     }
 
     //0x75
     fn ld_hl_l(&mut self) {
-        self.memory.write_byte(self.register.get_hl(), self.register.L);
+        self.write_byte(self.register.get_hl(), self.register.L);
     }
 
     //0x76
     fn halt(&mut self) {
-        self.halted = true;
+        // HALT bug: with IME off and an interrupt already pending the CPU doesn't
+        // halt, and the byte after HALT is fetched twice because PC fails to increment
+        if !self.memory.master && (self.memory.enable & self.memory.flags & 0x1f) != 0 {
+            self.halt_bug = true;
+        } else {
+            self.halted = true;
+        }
     }
 
     //0x77
     fn ld_hl_a(&mut self) {
-        self.memory.write_byte(self.register.get_hl(), self.register.A);
+        self.write_byte(self.register.get_hl(), self.register.A);
     }
 
     //0x78
@@ -2013,11 +2471,11 @@ impl CPU {
 
     //0x7e
     fn ld_a_hl(&mut self) {
-        self.register.A = self.memory.read_byte(self.register.get_hl());
+        self.register.A = self.read_byte(self.register.get_hl());
     }
 
     fn ld_a_a(&mut self) {
-        self.register.A = self.register.A;
+        // Loading a register into itself changes nothing
     }
 
     //0x80
@@ -2058,7 +2516,7 @@ impl CPU {
 
     //0x86
     fn add_a_hl(&mut self) {
-        let v = self.memory.read_byte(self.register.get_hl());
+        let v = self.read_byte(self.register.get_hl());
         self.add_a(v);
     }
 
@@ -2106,7 +2564,7 @@ impl CPU {
 
     //0x8e
     fn adc_a_hl(&mut self) {
-        let v = self.memory.read_byte(self.register.get_hl());
+        let v = self.read_byte(self.register.get_hl());
         self.adc_a(v);
     }
 
@@ -2154,7 +2612,7 @@ impl CPU {
 
     //0x96
     fn sub_a_hl(&mut self) {
-        let v = self.memory.read_byte(self.register.get_hl());
+        let v = self.read_byte(self.register.get_hl());
         self.sub_a(v);
     }
 
@@ -2202,7 +2660,7 @@ impl CPU {
 
     //0x9e
     fn sbc_a_hl(&mut self) {
-        let v = self.memory.read_byte(self.register.get_hl());
+        let v = self.read_byte(self.register.get_hl());
         self.sbc_a(v);
     }
 
@@ -2250,7 +2708,7 @@ impl CPU {
 
     //0xa6
     fn and_hl(&mut self) {
-        let v = self.memory.read_byte(self.register.get_hl());
+        let v = self.read_byte(self.register.get_hl());
         self.and(v);
     }
 
@@ -2298,7 +2756,7 @@ impl CPU {
 
     //0xae
     fn xor_hl(&mut self) {
-        let v = self.memory.read_byte(self.register.get_hl());
+        let v = self.read_byte(self.register.get_hl());
         self.xor(v);
     }
 
@@ -2346,7 +2804,7 @@ impl CPU {
 
     //0xb6
     fn or_hl(&mut self) {
-        let v = self.memory.read_byte(self.register.get_hl());
+        let v = self.read_byte(self.register.get_hl());
         self.or(v);
     }
 
@@ -2394,7 +2852,7 @@ impl CPU {
 
     //0xbe
     fn cp_hl(&mut self) {
-        let v = self.memory.read_byte(self.register.get_hl());
+        let v = self.read_byte(self.register.get_hl());
         self.cp(v);
     }
 
@@ -2405,10 +2863,12 @@ impl CPU {
     }
 
     //0xc0
-    fn ret_nz(&mut self) {
-        if !self.register.flag_get(Z) { 
+    fn ret_nz(&mut self) -> bool {
+        let taken = !self.register.flag_get(Z);
+        if taken {
             self.register.PC = self.pop_stack();
         }
+        taken
     }
 
     //0xc1
@@ -2418,10 +2878,12 @@ impl CPU {
     }
 
     //0xc2
-    fn jp_nz_nn(&mut self, operand: u16) {
-        if !self.register.flag_get(Z) { 
+    fn jp_nz_nn(&mut self, operand: u16) -> bool {
+        let taken = !self.register.flag_get(Z);
+        if taken {
             self.register.PC = operand;
         }
+        taken
     }
 
     //0xc3 
@@ -2430,12 +2892,14 @@ impl CPU {
     }
 
     //0xc4 
-    fn call_nz_nn(&mut self, operand: u16) {
-        if !self.register.flag_get(Z) {
+    fn call_nz_nn(&mut self, operand: u16) -> bool {
+        let taken = !self.register.flag_get(Z);
+        if taken {
             let v = self.register.PC;
             self.push_stack(v);
             self.register.PC = operand;
         }
+        taken
     }
 
     //0xc5
@@ -2457,10 +2921,12 @@ impl CPU {
     }
 
     //0xc8
-    fn ret_z(&mut self) {
-        if self.register.flag_get(Z) { 
+    fn ret_z(&mut self) -> bool {
+        let taken = self.register.flag_get(Z);
+        if taken {
             self.register.PC = self.pop_stack();
         }
+        taken
     }
 
     //0xc9
@@ -2469,19 +2935,23 @@ impl CPU {
     }
 
     //0xca
-    fn jp_z_nn(&mut self, operand: u16) {
-        if self.register.flag_get(Z) {
+    fn jp_z_nn(&mut self, operand: u16) -> bool {
+        let taken = self.register.flag_get(Z);
+        if taken {
             self.register.PC = operand;
         }
+        taken
     }
 
     //0xcc
-    fn call_z_nn(&mut self, operand: u16) {
-        if self.register.flag_get(Z) {
+    fn call_z_nn(&mut self, operand: u16) -> bool {
+        let taken = self.register.flag_get(Z);
+        if taken {
             let v = self.register.PC;
             self.push_stack(v);
             self.register.PC = operand;
         }
+        taken
     }
 
     //0xcd
@@ -2493,7 +2963,7 @@ impl CPU {
 
     //0xce
     fn adc_a_n(&mut self, operand: u8) {
-        self.add_a(operand);
+        self.adc_a(operand);
     }
 
     //0xcf
@@ -2504,10 +2974,12 @@ impl CPU {
     }
 
     //0xd0
-    fn ret_nc(&mut self) {
-        if !self.register.flag_get(C) { 
+    fn ret_nc(&mut self) -> bool {
+        let taken = !self.register.flag_get(C);
+        if taken {
             self.register.PC = self.pop_stack();
         }
+        taken
     }
 
     //0xd1
@@ -2517,19 +2989,23 @@ impl CPU {
     }
 
     //0xd2
-    fn jp_nc_nn(&mut self, operand: u16) {
-        if !self.register.flag_get(C) { 
+    fn jp_nc_nn(&mut self, operand: u16) -> bool {
+        let taken = !self.register.flag_get(C);
+        if taken {
             self.register.PC = operand;
         }
+        taken
     }
 
     //0xd4
-    fn call_nc_nn(&mut self, operand: u16) {
-        if !self.register.flag_get(C) {
+    fn call_nc_nn(&mut self, operand: u16) -> bool {
+        let taken = !self.register.flag_get(C);
+        if taken {
             let v = self.register.PC;
             self.push_stack(v);
             self.register.PC = operand;
         }
+        taken
     }
 
     //0xd5
@@ -2551,10 +3027,12 @@ impl CPU {
     }
 
     //0xd8
-    fn ret_c(&mut self) {
-        if self.register.flag_get(C) { 
+    fn ret_c(&mut self) -> bool {
+        let taken = self.register.flag_get(C);
+        if taken {
             self.register.PC = self.pop_stack();
         }
+        taken
     }
 
     //0xd9
@@ -2564,19 +3042,23 @@ impl CPU {
     }
 
     //0xda
-    fn jp_c_nn(&mut self, operand: u16) {
-        if self.register.flag_get(C) {
+    fn jp_c_nn(&mut self, operand: u16) -> bool {
+        let taken = self.register.flag_get(C);
+        if taken {
             self.register.PC = operand;
         }
+        taken
     }
 
     //0xdc
-    fn call_c_nn(&mut self, operand: u16) {
-        if self.register.flag_get(C) {
+    fn call_c_nn(&mut self, operand: u16) -> bool {
+        let taken = self.register.flag_get(C);
+        if taken {
             let v = self.register.PC;
             self.push_stack(v);
             self.register.PC = operand;
         }
+        taken
     }
 
     //0xde
@@ -2595,7 +3077,7 @@ impl CPU {
     fn ldh_n_a(&mut self, operand: u8) {
         let v = 0xff00 | (operand as u16);
         let a = self.register.A;
-        self.memory.write_byte(v, a);
+        self.write_byte(v, a);
     }
 
     //0xe1
@@ -2608,7 +3090,7 @@ impl CPU {
     fn ldh_c_a(&mut self) {
         let v = 0xff00 | (self.register.C as u16);
         let a = self.register.A;
-        self.memory.write_byte(v, a);
+        self.write_byte(v, a);
     }
 
     //0xe5
@@ -2631,12 +3113,17 @@ impl CPU {
 
     //0xe8
     fn add_sp_n(&mut self, operand: u8) {
-        let v = operand as i8 as i16 as u16;
-        if (self.register.SP & 0x000f) + (v & 0x000f) > 0x000f { self.register.flag_set(H) } else { self.register.flag_reset(H) }
-        if (self.register.SP & 0x00ff) + (v & 0x00ff) > 0x00ff { self.register.flag_set(C) } else { self.register.flag_reset(C) }
-        self.register.flag_reset(Z);
-        self.register.flag_reset(N);
-        self.register.SP += v;
+        self.register.SP = self.sp_plus_d(operand);
+    }
+
+    // SP plus the signed displacement d, shared by 0xe8 and 0xf8. H and C come
+    // from the unsigned addition of the low byte of SP and the raw operand
+    fn sp_plus_d(&mut self, operand: u8) -> u16 {
+        let sp = self.register.SP;
+        let h = (sp & 0x000f) + (operand as u16 & 0x000f) > 0x000f;
+        let c = (sp & 0x00ff) + operand as u16 > 0x00ff;
+        self.register.set_flags_znhc(Some(false), Some(false), Some(h), Some(c));
+        sp.wrapping_add(operand as i8 as i16 as u16)
     }
 
     //0xe9
@@ -2646,7 +3133,7 @@ impl CPU {
 
     //0xea
     fn ld_nn_a(&mut self, operand: u16) {
-        self.memory.write_byte(operand, self.register.A);
+        self.write_byte(operand, self.register.A);
     }
 
     //0xee
@@ -2664,7 +3151,7 @@ impl CPU {
     //0xf0
     fn ldh_a_n(&mut self, operand: u8) {
         let v = (operand as u16) | 0xff00;
-        self.register.A = self.memory.read_byte(v);
+        self.register.A = self.read_byte(v);
     }
 
     //0xf1
@@ -2675,7 +3162,9 @@ impl CPU {
 
     //0xf3
     fn di(&mut self) {
+        // Also cancels an EI that hasn't taken effect yet
         self.memory.master = false;
+        self.ime_pending = 0;
     }
     
     //0xf5
@@ -2698,11 +3187,7 @@ impl CPU {
 
     //0xf8
     fn ldhl_sp_d(&mut self, operand: u8) {
-        let v = self.register.SP + operand as u16;
-        if (self.register.SP & 0x000f) + (v & 0x000f) > 0x000f { self.register.flag_set(H) } else { self.register.flag_reset(H) }
-        if (self.register.SP & 0x00ff) + (v & 0x00ff) > 0x00ff { self.register.flag_set(C) } else { self.register.flag_reset(C) }
-        self.register.flag_reset(Z);
-        self.register.flag_reset(N);
+        let v = self.sp_plus_d(operand);
         self.register.set_hl(v);
     }
 
@@ -2713,12 +3198,16 @@ impl CPU {
 
     //0xfa
     fn ld_a_nn(&mut self, operand: u16) {
-        self.register.A = self.memory.read_byte(operand);
+        self.register.A = self.read_byte(operand);
     }
 
     //0xfb
     fn ei(&mut self) {
-        self.memory.master = true;
+        // IME is only set after the instruction following EI. A second EI inside
+        // the window doesn't push it back
+        if !self.memory.master && self.ime_pending == 0 {
+            self.ime_pending = 2;
+        }
     }
 
     //0xfe
@@ -2732,4 +3221,688 @@ impl CPU {
         self.push_stack(v);
         self.register.PC = 0x38;
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // Headless CPU running `code` from 0xc000 in work RAM
+    fn run_code(code: &[u8]) -> CPU {
+        let mut cpu = CPU::new_headless();
+        cpu.load_raw(code, 0xc000, 0xc000);
+        cpu
+    }
+
+    #[test]
+    fn run_until_vblank_runs_one_frame() {
+        let mut cpu = run_code(&[0x18, 0xfe]); // JR -2
+        cpu.run_until_vblank();
+        let cycles = cpu.run_until_vblank();
+        assert!((FRAME_CYCLES - 12..=FRAME_CYCLES + 12).contains(&cycles), "{} cycles", cycles);
+    }
+
+    #[test]
+    fn capture_next_frame_returns_a_full_frame() {
+        // LD A,0xFF; LDH (0x47),A; JR -2
+        let mut cpu = run_code(&[0x3e, 0xff, 0xe0, 0x47, 0x18, 0xfe]);
+        cpu.capture_next_frame();
+        let start = cpu.total_cycles();
+        let frame = cpu.capture_next_frame();
+        let cycles = (cpu.total_cycles() - start) as u32;
+        assert!((FRAME_CYCLES - 12..=FRAME_CYCLES + 12).contains(&cycles), "{} cycles", cycles);
+        assert!(frame.iter().all(|&shade| shade == 0));
+    }
+
+    #[test]
+    fn cycle_counter_runs_past_u32() {
+        let mut cpu = run_code(&[0x18, 0xfe]);
+        cpu.total_cycles = u32::MAX as u64 - 100;
+        cpu.run_until_vblank();
+        cpu.run_until_vblank();
+        assert!(cpu.total_cycles() > u32::MAX as u64 + FRAME_CYCLES as u64 / 2);
+        assert!(cpu.ticks < FRAME_CYCLES);
+    }
+
+    #[test]
+    fn halt_bug_runs_the_next_instruction_twice() {
+        let mut cpu = run_code(&[0x76, 0x3c, 0x18, 0xfe]); // HALT, INC A, JR -2
+        cpu.register.A = 0;
+        cpu.poke(0xffff, IFlags::VBLANK as u8);
+        cpu.poke(0xff0f, IFlags::VBLANK as u8);
+        cpu.step();
+        assert!(!cpu.is_halted());
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.register.A, 2);
+        assert_eq!(cpu.register.PC, 0xc002);
+    }
+
+    // Copies P1 into BGP forever, so the frames follow the movie input
+    const JOYPAD_TO_BGP: [u8; 10] = [
+        0x3e, 0x10, // LD A,0x10
+        0xe0, 0x00, // LDH (0x00),A
+        0xf0, 0x00, // LDH A,(0x00)
+        0xe0, 0x47, // LDH (0x47),A
+        0x18, 0xfa, // JR -6
+    ];
+
+    #[test]
+    fn movie_replays_identically() {
+        let mut movie = Movie::new(1234);
+        for i in 0..20 {
+            movie.push(if i % 4 == 0 { 0x10 } else { 0x00 });
+        }
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        let mut cpu = CPU::new_headless();
+        cpu.load_raw(&JOYPAD_TO_BGP, 0x100, 0x100);
+        let first = cpu.run_movie(&movie);
+        let second = cpu.run_movie(&movie);
+        assert_eq!(first.len(), 20);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn ei_services_after_the_next_instruction() {
+        let mut cpu = run_code(&[0xfb, 0x00, 0x00, 0x18, 0xfe]); // EI, NOP, NOP, JR -2
+        cpu.poke(0xffff, IFlags::VBLANK as u8);
+        cpu.poke(0xff0f, IFlags::VBLANK as u8);
+        cpu.step();
+        assert_eq!(cpu.register.PC, 0xc001);
+        for _ in 0..2 {
+            if cpu.register.PC == 0x0040 { break; }
+            cpu.step();
+        }
+        assert_eq!(cpu.register.PC, 0x0040);
+        assert_eq!(cpu.memory.read_short(cpu.register.SP), 0xc002);
+    }
+
+    #[test]
+    fn di_cancels_a_pending_ei() {
+        let mut cpu = run_code(&[0xfb, 0xf3, 0x00, 0x18, 0xfe]); // EI, DI, NOP, JR -2
+        cpu.poke(0xffff, IFlags::VBLANK as u8);
+        cpu.poke(0xff0f, IFlags::VBLANK as u8);
+        for _ in 0..6 {
+            cpu.step();
+            assert!(cpu.register.PC >= 0xc000, "serviced at {:04x}", cpu.register.PC);
+        }
+        assert!(!cpu.memory.master);
+    }
+
+    #[test]
+    fn add_hl_carries_from_bits_11_and_15() {
+        let mut cpu = run_code(&[0x09, 0x09]); // ADD HL,BC twice
+        cpu.register.set_hl(0x0fff);
+        cpu.register.set_bc(0x0001);
+        cpu.register.flag_set(Z);
+        cpu.step();
+        assert_eq!(cpu.register.get_hl(), 0x1000);
+        assert!(cpu.register.flag_get(H) && !cpu.register.flag_get(C));
+        assert!(cpu.register.flag_get(Z) && !cpu.register.flag_get(N));
+
+        cpu.register.set_hl(0xffff);
+        cpu.step();
+        assert_eq!(cpu.register.get_hl(), 0x0000);
+        assert!(cpu.register.flag_get(H) && cpu.register.flag_get(C));
+    }
+
+    #[test]
+    fn conditional_branches_take_extra_cycles_when_taken() {
+        // (opcode, flag, taken when set, taken cycles, not taken cycles)
+        let branches = [(0x20, Z, false, 12, 8), (0x28, Z, true, 12, 8),
+                        (0x30, C, false, 12, 8), (0x38, C, true, 12, 8),
+                        (0xc2, Z, false, 16, 12), (0xca, Z, true, 16, 12),
+                        (0xd2, C, false, 16, 12), (0xda, C, true, 16, 12),
+                        (0xc4, Z, false, 24, 12), (0xcc, Z, true, 24, 12),
+                        (0xd4, C, false, 24, 12), (0xdc, C, true, 24, 12),
+                        (0xc0, Z, false, 20, 8), (0xc8, Z, true, 20, 8),
+                        (0xd0, C, false, 20, 8), (0xd8, C, true, 20, 8)];
+        for &(op, flag, when_set, taken, not_taken) in branches.iter() {
+            for &set in [false, true].iter() {
+                let mut cpu = run_code(&[op, 0x00, 0xc0]);
+                if set { cpu.register.flag_set(flag); } else { cpu.register.flag_reset(flag); }
+                let expected = if set == when_set { taken } else { not_taken };
+                assert_eq!(cpu.step(), expected, "opcode {:02X} with flag {}", op, set);
+            }
+        }
+    }
+
+    #[test]
+    fn halt_returns_to_the_next_instruction() {
+        let mut cpu = run_code(&[0xfb, 0x76, 0x00, 0x18, 0xfe]); // EI, HALT, NOP, JR -2
+        cpu.poke(0xffff, IFlags::VBLANK as u8);
+        cpu.poke(0xff0f, 0);
+        let mut steps = 0;
+        while cpu.register.PC != 0x0040 {
+            cpu.step();
+            steps += 1;
+            assert!(steps < 100000, "VBlank never serviced");
+        }
+        assert_eq!(cpu.memory.read_short(cpu.register.SP), 0xc002);
+    }
+
+    #[test]
+    fn add_sp_wraps_past_0xffff() {
+        let mut cpu = run_code(&[0xe8, 0x01]); // ADD SP,1
+        cpu.register.SP = 0xffff;
+        cpu.register.flag_set(Z);
+        cpu.step();
+        assert_eq!(cpu.register.SP, 0x0000);
+        assert!(cpu.register.flag_get(H) && cpu.register.flag_get(C));
+        assert!(!cpu.register.flag_get(Z) && !cpu.register.flag_get(N));
+    }
+
+    #[test]
+    fn second_interrupt_waits_for_ime() {
+        let mut rom = vec![0; 0x102];
+        // VBlank handler: NOP, NOP, NOP, EI, JR -2
+        rom[0x40..0x46].copy_from_slice(&[0x00, 0x00, 0x00, 0xfb, 0x18, 0xfe]);
+        rom[0x48..0x4a].copy_from_slice(&[0x18, 0xfe]);
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xfe]);
+        let mut cpu = CPU::new_headless();
+        cpu.load_raw(&rom, 0x0000, 0x100);
+        let both = IFlags::VBLANK as u8 | IFlags::LCDCSTATUS as u8;
+        cpu.poke(0xffff, both);
+        cpu.poke(0xff0f, both);
+        cpu.memory.master = true;
+
+        let mut ran_ei = false;
+        for _ in 0..10 {
+            let pc = cpu.register.PC;
+            cpu.step();
+            ran_ei |= pc == 0x43;
+            if cpu.register.PC == 0x48 { break; }
+            assert!(cpu.register.PC >= 0x40 && cpu.register.PC < 0x46, "at {:04x}", cpu.register.PC);
+            assert!(cpu.peek(0xff0f) & IFlags::LCDCSTATUS as u8 != 0);
+        }
+        assert_eq!(cpu.register.PC, 0x48);
+        assert!(ran_ei);
+    }
+
+    #[test]
+    fn lcd_off_holds_ly_at_0() {
+        // XOR A; LDH (0x40),A; LD A,0x78; LDH (0x41),A; JR -2
+        let mut cpu = run_code(&[0xaf, 0xe0, 0x40, 0x3e, 0x78, 0xe0, 0x41, 0x18, 0xfe]);
+        for _ in 0..4 {
+            cpu.step();
+        }
+        cpu.poke(0xff0f, 0);
+        for _ in 0..20000 {
+            cpu.step();
+            assert_eq!(cpu.peek(0xff44), 0);
+        }
+        assert_eq!(cpu.peek(0xff0f) & (IFlags::VBLANK as u8 | IFlags::LCDCSTATUS as u8), 0);
+    }
+
+    #[test]
+    fn low_nibble_of_f_stays_clear() {
+        // SCF, CCF, CPL, SCF, PUSH AF, POP BC, LD BC,0x12ff, PUSH BC, POP AF
+        let mut cpu = run_code(&[0x37, 0x3f, 0x2f, 0x37, 0xf5, 0xc1, 0x01, 0xff, 0x12, 0xc5, 0xf1]);
+        for _ in 0..4 {
+            cpu.step();
+            assert_eq!(cpu.register.F & 0x0f, 0);
+        }
+        let flags = cpu.register.F;
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.register.C, flags);
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert_eq!(cpu.register.A, 0x12);
+        assert_eq!(cpu.register.F, 0xf0);
+    }
+
+    #[test]
+    fn cp_sets_the_same_flags_as_sub() {
+        let mut cpu = run_code(&[0x90, 0xb8]); // SUB B, CP B
+        for a in 0..256 {
+            for b in 0..256 {
+                cpu.register.PC = 0xc000;
+                cpu.register.A = a as u8;
+                cpu.register.B = b as u8;
+                cpu.step();
+                let flags = cpu.register.F;
+                cpu.register.A = a as u8;
+                cpu.step();
+                assert_eq!(cpu.register.F, flags, "A {:02x} B {:02x}", a, b);
+                assert_eq!(cpu.register.A, a as u8);
+            }
+        }
+    }
+
+    #[test]
+    fn load_raw_runs_a_snippet_from_work_ram() {
+        let mut cpu = CPU::new_headless();
+        cpu.load_raw(&[0x3e, 0x41, 0x3c, 0x47], 0xc000, 0xc000); // LD A,0x41; INC A; LD B,A
+        assert_eq!(cpu.peek(0xc003), 0x47);
+        cpu.set_pc(0xc002);
+        cpu.register.A = 0x10;
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.register.PC, 0xc004);
+        assert_eq!(cpu.register.B, 0x11);
+
+        cpu.set_pc(0xc000);
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert_eq!(cpu.register.B, 0x42);
+    }
+
+    #[test]
+    fn stop_skips_a_nonzero_second_byte() {
+        let mut cpu = run_code(&[0x10, 0x42, 0x00]);
+        cpu.set_debugging(true);
+        cpu.step();
+        assert_eq!(cpu.register.PC, 0xc002);
+        assert!(cpu.is_stopped());
+    }
+
+    struct CountingSink(Rc<Cell<u32>>);
+
+    impl FrameSink for CountingSink {
+        fn present(&mut self, _frame: &[u8; 160 * 144 * 3]) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn frames_are_presented_once_per_vblank() {
+        let presents = Rc::new(Cell::new(0));
+        let mut cpu = CPU::new(Box::new(CountingSink(presents.clone())));
+        cpu.load_raw(&[0x18, 0xfe], 0xc000, 0xc000);
+        // Scanline rendering only fills the buffer, the frontend presents
+        for frame in 0..3 {
+            cpu.run_until_vblank();
+            assert_eq!(presents.get(), frame);
+            cpu.present_frame();
+            assert_eq!(presents.get(), frame + 1);
+        }
+    }
+
+    #[test]
+    fn stack_floor_catches_a_runaway_push() {
+        let mut cpu = run_code(&[0xc5, 0x18, 0xfd]); // PUSH BC, JR -3
+        let err = cpu.run_with_stack_floor(10, 0xff80).unwrap_err();
+        assert_eq!((err.pc, err.sp), (0xc000, 0xff7e));
+
+        let mut cpu = run_code(&[0x18, 0xfe]);
+        assert!(cpu.run_with_stack_floor(10, 0xff80).is_ok());
+    }
+
+    #[test]
+    fn interrupts_jump_to_their_vector() {
+        for (i, &vector) in INTERRUPT_VECTORS.iter().enumerate() {
+            let mut cpu = run_code(&[0x18, 0xfe]);
+            cpu.poke(0xffff, 0x1f);
+            cpu.poke(0xff0f, 0xe0 | (1 << i));
+            cpu.memory.master = true;
+            cpu.step();
+            assert_eq!(cpu.register.PC, vector);
+            assert_eq!(cpu.register.PC, 0x40 + 8 * i as u16);
+            assert_eq!(cpu.peek(0xff0f) & 0x1f, 0, "interrupt {}", i);
+            assert!(!cpu.memory.master);
+            assert_eq!(cpu.memory.read_short(cpu.register.SP), 0xc000);
+        }
+    }
+
+    #[test]
+    fn cb_rotates_at_edge_values() {
+        // (CB opcode on B, value, carry in, result, carry out)
+        let cases = [(0x00, 0x00, true, 0x00, false), (0x00, 0x80, false, 0x01, true),
+                     (0x00, 0x01, true, 0x02, false), (0x00, 0xff, false, 0xff, true),
+                     (0x08, 0x00, true, 0x00, false), (0x08, 0x01, false, 0x80, true),
+                     (0x08, 0x80, true, 0x40, false), (0x08, 0xff, false, 0xff, true),
+                     (0x10, 0x80, false, 0x00, true), (0x10, 0x00, true, 0x01, false),
+                     (0x10, 0x7f, true, 0xff, false), (0x10, 0xff, false, 0xfe, true),
+                     (0x18, 0x01, false, 0x00, true), (0x18, 0x00, true, 0x80, false),
+                     (0x18, 0xfe, true, 0xff, false), (0x18, 0xff, false, 0x7f, true)];
+        for &(op, value, carry, result, carry_out) in cases.iter() {
+            let mut cpu = run_code(&[0xcb, op]);
+            cpu.register.B = value;
+            cpu.register.F = if carry { 0x70 } else { 0x60 };
+            cpu.step();
+            let case = format!("CB {:02X} on {:02X}, carry {}", op, value, carry);
+            assert_eq!(cpu.register.B, result, "{}", case);
+            assert_eq!(cpu.register.flag_get(C), carry_out, "{}", case);
+            assert_eq!(cpu.register.flag_get(Z), result == 0, "{}", case);
+            assert!(!cpu.register.flag_get(N) && !cpu.register.flag_get(H), "{}", case);
+        }
+    }
+
+    #[test]
+    fn self_loads_only_advance_pc() {
+        // LD B,B; LD C,C; LD D,D; LD E,E; LD H,H; LD L,L; LD A,A
+        let mut cpu = run_code(&[0x40, 0x49, 0x52, 0x5b, 0x64, 0x6d, 0x7f]);
+        cpu.register.set_bc(0x1234);
+        cpu.register.set_de(0x5678);
+        cpu.register.set_hl(0x9abc);
+        cpu.register.A = 0xde;
+        for i in 1..8 {
+            assert_eq!(cpu.step(), 4);
+            assert_eq!(cpu.register.PC, 0xc000 + i);
+        }
+        assert_eq!((cpu.register.get_bc(), cpu.register.get_de(), cpu.register.get_hl()), (0x1234, 0x5678, 0x9abc));
+        assert_eq!(cpu.register.A, 0xde);
+    }
+
+    #[test]
+    fn add_carries_out_of_bit_7() {
+        let mut cpu = run_code(&[0x80, 0x88]); // ADD A,B; ADC A,B
+        cpu.register.A = 0xff;
+        cpu.register.B = 0x01;
+        cpu.step();
+        assert_eq!(cpu.register.A, 0x00);
+        assert!(cpu.register.flag_get(Z) && cpu.register.flag_get(C) && cpu.register.flag_get(H));
+
+        cpu.register.A = 0xfe;
+        cpu.step();
+        assert_eq!(cpu.register.A, 0x00);
+        assert!(cpu.register.flag_get(Z) && cpu.register.flag_get(C));
+    }
+
+    #[test]
+    fn adc_and_sbc_set_z_from_the_result() {
+        let mut cpu = run_code(&[0x88, 0x98]); // ADC A,B; SBC A,B
+        cpu.register.A = 0x00;
+        cpu.register.B = 0x00;
+        cpu.register.flag_set(C);
+        cpu.step();
+        assert_eq!(cpu.register.A, 0x01);
+        assert!(!cpu.register.flag_get(Z) && !cpu.register.flag_get(C));
+
+        cpu.register.flag_set(C);
+        cpu.step();
+        assert_eq!(cpu.register.A, 0x00);
+        assert!(cpu.register.flag_get(Z) && cpu.register.flag_get(N) && !cpu.register.flag_get(C));
+    }
+
+    #[test]
+    fn add_hl_flags_for_every_register_pair() {
+        // ADD HL,DE; ADD HL,HL; ADD HL,SP
+        let mut cpu = run_code(&[0x19, 0x29, 0x39]);
+        cpu.register.set_hl(0x0800);
+        cpu.register.set_de(0x0800);
+        cpu.step();
+        assert_eq!(cpu.register.get_hl(), 0x1000);
+        assert!(cpu.register.flag_get(H) && !cpu.register.flag_get(C));
+
+        cpu.register.set_hl(0x8000);
+        cpu.step();
+        assert_eq!(cpu.register.get_hl(), 0x0000);
+        assert!(!cpu.register.flag_get(H) && cpu.register.flag_get(C));
+
+        cpu.register.set_hl(0xffff);
+        cpu.register.SP = 0x0001;
+        cpu.step();
+        assert_eq!(cpu.register.get_hl(), 0x0000);
+        assert!(cpu.register.flag_get(H) && cpu.register.flag_get(C));
+    }
+
+    #[test]
+    fn adc_immediate_adds_the_carry() {
+        let mut cpu = run_code(&[0xce, 0x0f]); // ADC A,0x0F
+        cpu.register.A = 0x00;
+        cpu.register.flag_set(C);
+        cpu.step();
+        assert_eq!(cpu.register.A, 0x10);
+        assert!(cpu.register.flag_get(H) && !cpu.register.flag_get(C));
+    }
+
+    #[test]
+    fn oam_dma_takes_a_cycle_per_byte_when_cycle_stepped() {
+        let code = [0x3e, 0xc1, 0xe0, 0x46, 0xfa, 0x00, 0xfe, 0x47, 0x0e, 0x28,
+                    0x0d, 0x20, 0xfd, 0xfa, 0x00, 0xfe, 0x57, 0x18, 0xfe];
+        for &stepped in &[false, true] {
+            let mut cpu = run_code(&code);
+            cpu.set_cycle_stepped(stepped);
+            cpu.poke(0xc100, 0x42);
+            for _ in 0..100 {
+                cpu.step();
+            }
+            // Mid-transfer OAM reads 0xff, the copy is done long before the loop ends
+            assert_eq!(cpu.register.B, if stepped { 0xff } else { 0x42 });
+            assert_eq!(cpu.register.D, 0x42);
+        }
+    }
+
+    #[test]
+    fn stop_takes_one_cycle_when_cycle_stepped() {
+        for &stepped in &[false, true] {
+            let mut cpu = run_code(&[0x10, 0x00]);
+            cpu.set_cycle_stepped(stepped);
+            let start = cpu.total_cycles();
+            assert_eq!(cpu.step(), 4);
+            assert_eq!(cpu.total_cycles() - start, 4, "cycle stepped {}", stepped);
+            assert_eq!(cpu.register.PC, 0xc002);
+            assert!(cpu.is_stopped());
+        }
+    }
+
+    #[test]
+    fn halt_waits_for_a_pending_interrupt() {
+        let mut cpu = run_code(&[0x76, 0x04, 0x18, 0xfe]); // HALT, INC B, JR -2
+        cpu.poke(0xffff, IFlags::VBLANK as u8);
+        cpu.poke(0xff0f, 0);
+        cpu.register.B = 0;
+        cpu.step();
+        assert!(cpu.is_halted());
+        for _ in 0..10 {
+            assert_eq!(cpu.step(), 4);
+            assert!(cpu.is_halted());
+            assert_eq!(cpu.register.PC, 0xc001);
+        }
+        // IME is off, so the CPU just wakes up and carries on after the HALT
+        cpu.poke(0xff0f, IFlags::VBLANK as u8);
+        cpu.step();
+        cpu.step();
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.register.B, 1);
+    }
+
+    #[test]
+    fn ei_sets_ime_after_the_next_instruction() {
+        let mut cpu = run_code(&[0xfb, 0x00, 0x00]); // EI, NOP, NOP
+        cpu.poke(0xffff, 0);
+        cpu.step();
+        assert!(!cpu.memory.master);
+        cpu.step();
+        assert!(cpu.memory.master);
+    }
+
+    #[test]
+    fn migrated_add_and_sub_match_adc_and_sbc_without_carry() {
+        // adc_a and sbc_a still set each flag by hand, with C clear they must agree
+        let mut cpu = CPU::new_headless();
+        for a in 0..256 {
+            for value in 0..256 {
+                let (a, value) = (a as u8, value as u8);
+                for &(migrated, manual) in &[(CPU::add_a as fn(&mut CPU, u8), CPU::adc_a as fn(&mut CPU, u8)),
+                                             (CPU::sub_a, CPU::sbc_a)] {
+                    cpu.register.A = a;
+                    cpu.register.F = 0;
+                    manual(&mut cpu, value);
+                    let expected = (cpu.register.A, cpu.register.F);
+                    cpu.register.A = a;
+                    cpu.register.F = 0;
+                    migrated(&mut cpu, value);
+                    assert_eq!((cpu.register.A, cpu.register.F), expected, "a {:02x} value {:02x}", a, value);
+                }
+            }
+        }
+    }
+
+    // Mixes the directions, DIV and the seeded work RAM into SCX, BGP and the
+    // tile data every iteration, so any stray state shows in the frames
+    const DETERMINISM_LOOP: [u8; 32] = [
+        0x31, 0xfe, 0xff,   // LD SP,0xFFFE
+        0x3e, 0x91,         // LD A,0x91
+        0xe0, 0x40,         // LDH (0x40),A
+        0x3e, 0x20,         // loop: LD A,0x20
+        0xe0, 0x00,         // LDH (0x00),A
+        0xf0, 0x00,         // LDH A,(0x00)
+        0x47,               // LD B,A
+        0xf0, 0x04,         // LDH A,(0x04)
+        0xa8,               // XOR B
+        0x21, 0x00, 0xc0,   // LD HL,0xC000
+        0x86,               // ADD A,(HL)
+        0x77,               // LD (HL),A
+        0xe0, 0x43,         // LDH (0x43),A
+        0xe0, 0x47,         // LDH (0x47),A
+        0x5f,               // LD E,A
+        0x16, 0x80,         // LD D,0x80
+        0x12,               // LD (DE),A
+        0x18, 0xe7,         // JR loop
+    ];
+
+    // A 32KB ROM only cartridge jumping from 0x100 to DETERMINISM_LOOP at 0x150
+    fn determinism_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xc3, 0x50, 0x01]); // NOP; JP 0x150
+        rom[0x134..0x13f].copy_from_slice(b"DETERMINISM");
+        rom[0x150..0x150 + DETERMINISM_LOOP.len()].copy_from_slice(&DETERMINISM_LOOP);
+        rom
+    }
+
+    #[test]
+    fn test_rom_runs_deterministically() {
+        let mut movie = Movie::new(0xdead);
+        for i in 0..120 {
+            movie.push(if (i / 8) % 3 == 0 { 0x0a } else { 0x00 });
+        }
+        let mut cpu = CPU::new_headless();
+        cpu.load_rom_data(determinism_rom()).unwrap();
+        let first = cpu.run_movie(&movie);
+        let second = cpu.run_movie(&movie);
+        assert_eq!(first.len(), 120);
+        assert!(first.windows(2).any(|w| w[0] != w[1]), "the frames never change");
+        assert_eq!(first, second);
+        assert_eq!(cpu.check_determinism(&movie), None);
+    }
+
+    #[test]
+    fn reset_clears_ie_cycles_and_frame_ready() {
+        let mut cpu = run_code(&[0x18, 0xfe]);
+        cpu.poke(0xffff, 0x1f);
+        cpu.run_until_vblank();
+        assert!(cpu.total_cycles() != 0 && cpu.frame_ready());
+        cpu.reset();
+        assert_eq!(cpu.peek(0xffff), 0);
+        assert_eq!(cpu.memory.enable, 0);
+        assert_eq!(cpu.total_cycles(), 0);
+        assert!(!cpu.frame_ready());
+    }
+
+    #[test]
+    fn save_states_round_trip_and_reject_other_sizes() {
+        for &(rom_type, ram_size) in &[(0x00, 0x00), (0x03, 0x03), (0x13, 0x03)] {
+            let mut rom = vec![0; 0x8000];
+            rom[0x100] = 0x3c; // INC A
+            rom[0x101] = 0x18; // JR -3
+            rom[0x102] = 0xfd;
+            rom[0x147] = rom_type;
+            rom[0x149] = ram_size;
+            let mut cpu = CPU::new_headless();
+            cpu.load_rom_data(rom).unwrap();
+            cpu.run_until_vblank();
+            let state = cpu.save_state();
+            assert_eq!(state.len(), cpu.state_size(), "type {:02x}", rom_type);
+
+            cpu.run_until_vblank();
+            let later = cpu.save_state();
+            assert!(later != state);
+            for bad in &[&state[..state.len() - 1], &[&state[..], &[0]].concat()[..]] {
+                assert!(cpu.load_state(bad).is_err());
+                assert!(cpu.save_state() == later, "a rejected state changed the machine");
+            }
+            cpu.load_state(&state).unwrap();
+            assert!(cpu.save_state() == state);
+        }
+    }
+
+    #[test]
+    fn rewind_captures_every_interval_frames() {
+        let mut cpu = run_code(&[0x3c, 0x18, 0xfd]); // INC A, JR -3
+        cpu.set_rewind(3, 4);
+        for frame in 1..10 {
+            cpu.run_until_vblank();
+            assert_eq!(cpu.rewind_len(), frame / 3, "frame {}", frame);
+        }
+    }
+
+    #[test]
+    fn rewind_evicts_the_oldest_state() {
+        let mut cpu = run_code(&[0x3c, 0x18, 0xfd]);
+        cpu.set_rewind(1, 2);
+        let mut a = Vec::new();
+        for _ in 0..3 {
+            cpu.run_until_vblank();
+            a.push(cpu.register.A);
+        }
+        assert_eq!(cpu.rewind_len(), 2);
+        assert!(cpu.rewind());
+        assert_eq!(cpu.register.A, a[2]);
+        assert!(cpu.rewind());
+        assert_eq!(cpu.register.A, a[1]);
+        assert!(!cpu.rewind_available());
+        assert!(!cpu.rewind());
+    }
+
+    #[test]
+    fn rewind_restores_registers_and_memory() {
+        let mut cpu = run_code(&[0x3c, 0x18, 0xfd]);
+        cpu.set_rewind(1, 4);
+        cpu.poke(0xc100, 0x11);
+        cpu.run_until_vblank();
+        let (a, pc) = (cpu.register.A, cpu.register.PC);
+        cpu.run_until_vblank();
+        cpu.poke(0xc100, 0x22);
+        assert!(cpu.rewind());
+        assert!(cpu.rewind());
+        assert_eq!(cpu.rewind_len(), 0);
+        assert_eq!((cpu.register.A, cpu.register.PC), (a, pc));
+        assert_eq!(cpu.peek(0xc100), 0x11);
+    }
+
+    #[test]
+    fn rewind_needs_a_captured_state() {
+        let mut cpu = run_code(&[0x3c, 0x18, 0xfd]);
+        assert!(!cpu.rewind());
+        cpu.set_rewind(1, 4);
+        cpu.run_until_vblank();
+        cpu.run_until_vblank();
+        assert_eq!(cpu.rewind_len(), 2);
+        cpu.reset();
+        assert_eq!(cpu.rewind_len(), 0);
+        assert!(!cpu.rewind());
+    }
+
+    #[test]
+    fn sp_increments_wrap_past_0xffff() {
+        let mut cpu = run_code(&[0x33, 0xf8, 0x01]); // INC SP, LD HL,SP+1
+        cpu.register.SP = 0xffff;
+        cpu.step();
+        assert_eq!(cpu.register.SP, 0x0000);
+        cpu.register.SP = 0xffff;
+        cpu.step();
+        assert_eq!(cpu.register.get_hl(), 0x0000);
+        assert_eq!(cpu.register.SP, 0xffff);
+    }
+
+    #[test]
+    fn negative_sp_offsets_take_flags_from_the_low_byte() {
+        let mut cpu = run_code(&[0xe8, 0xf8, 0xf8, 0xf8]); // ADD SP,-8, LD HL,SP-8
+        for i in 0..2 {
+            cpu.register.SP = 0xfff8;
+            cpu.register.F = Z as u8 | N as u8;
+            cpu.step();
+            assert_eq!(if i == 0 { cpu.register.SP } else { cpu.register.get_hl() }, 0xfff0);
+            // 0xf8 + 0xf8 carries out of bits 3 and 7
+            assert!(cpu.register.flag_get(H) && cpu.register.flag_get(C));
+            assert!(!cpu.register.flag_get(Z) && !cpu.register.flag_get(N));
+        }
+    }
+}
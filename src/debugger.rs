@@ -0,0 +1,133 @@
+use cpu::CPU;
+use disasm::SymbolTable;
+
+use std::io;
+use std::io::prelude::*;
+use std::cmp;
+
+// Interactive command line debugger, started with --debug
+//   s            step one instruction
+//   c            continue until a breakpoint, needs one to be set
+//   b ADDR       toggle a breakpoint
+//   x ADDR LEN   examine memory
+//   r            show registers
+//   q            quit
+pub struct Debugger {
+    breakpoints: Vec<u16>,
+    symbols: Option<SymbolTable>,
+}
+
+fn parse_hex(arg: Option<&str>) -> Option<u16> {
+    arg.and_then(|a| u16::from_str_radix(a.trim_start_matches("0x").trim_start_matches('$'), 16).ok())
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            symbols: None,
+        }
+    }
+
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = Some(symbols);
+    }
+
+    pub fn run<R: BufRead, W: Write>(&mut self, cpu: &mut CPU, input: R, mut out: W) -> io::Result<()> {
+        self.print_instruction(cpu, &mut out)?;
+        for line in input.lines() {
+            let line = line?;
+            let mut args = line.split_whitespace();
+            match args.next() {
+                Some("s") => {
+                    cpu.cpu_cycle();
+                    self.print_instruction(cpu, &mut out)?;
+                    self.print_registers(cpu, &mut out)?;
+                }
+                // Without a breakpoint there'd be no way back to the prompt
+                Some("c") if self.breakpoints.is_empty() => writeln!(out, "No breakpoints set")?,
+                Some("c") => {
+                    loop {
+                        if cpu.cpu_cycle() { cpu.present_frame(); }
+                        if cpu.is_stopped() || self.breakpoints.contains(&cpu.snapshot().pc) { break; }
+                    }
+                    self.print_instruction(cpu, &mut out)?;
+                }
+                Some("b") => match parse_hex(args.next()) {
+                    Some(addr) => {
+                        if let Some(i) = self.breakpoints.iter().position(|b| *b == addr) {
+                            self.breakpoints.remove(i);
+                            writeln!(out, "Breakpoint {:04X} removed", addr)?;
+                        } else {
+                            self.breakpoints.push(addr);
+                            writeln!(out, "Breakpoint {:04X} set", addr)?;
+                        }
+                    }
+                    None => writeln!(out, "Usage: b ADDR")?,
+                },
+                Some("x") => match (parse_hex(args.next()), parse_hex(args.next())) {
+                    (Some(addr), Some(len)) => self.print_memory(cpu, &mut out, addr, len)?,
+                    _ => writeln!(out, "Usage: x ADDR LEN")?,
+                },
+                Some("r") => self.print_registers(cpu, &mut out)?,
+                Some("q") => break,
+                Some(cmd) => writeln!(out, "Unknown command {}", cmd)?,
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn print_instruction<W: Write>(&self, cpu: &mut CPU, out: &mut W) -> io::Result<()> {
+        let pc = cpu.snapshot().pc;
+        let (text, _) = cpu.disassemble_at(pc, self.symbols.as_ref());
+        match self.symbols.as_ref().and_then(|s| s.lookup(pc)) {
+            Some(label) => writeln!(out, "{}:\n{:04X}  {}", label, pc, text),
+            None => writeln!(out, "{:04X}  {}", pc, text),
+        }
+    }
+
+    fn print_registers<W: Write>(&self, cpu: &CPU, out: &mut W) -> io::Result<()> {
+        let s = cpu.snapshot();
+        writeln!(out, "AF {:02X}{:02X} BC {:02X}{:02X} DE {:02X}{:02X} HL {:02X}{:02X} SP {:04X} PC {:04X} LY {:02X} IME {}",
+                 s.a, s.f, s.b, s.c, s.d, s.e, s.h, s.l, s.sp, s.pc, s.scanline, s.ime as u8)
+    }
+
+    fn print_memory<W: Write>(&self, cpu: &mut CPU, out: &mut W, addr: u16, len: u16) -> io::Result<()> {
+        for row in 0..(len as u32).div_ceil(16) {
+            let start = addr.wrapping_add((row * 16) as u16);
+            write!(out, "{:04X} ", start)?;
+            for i in 0..cmp::min(16, len as u32 - row * 16) {
+                write!(out, " {:02X}", cpu.peek(start.wrapping_add(i as u16)))?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripted_session() {
+        let mut cpu = CPU::new_headless();
+        cpu.load_raw(&[0x3e, 0x42, 0x00, 0x00, 0x18, 0xfe], 0xc000, 0xc000); // LD A,0x42; NOP; NOP; JR -2
+        let script = "s\nc\nb c003\nc\nx c000 6\nfoo\nq\ns\n";
+        let mut out = Vec::new();
+        Debugger::new().run(&mut cpu, script.as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "C000  LD A,$42");
+        assert_eq!(lines[1], "C002  NOP");
+        assert!(lines[2].starts_with("AF 42"));
+        assert_eq!(&lines[3..], &["No breakpoints set", "Breakpoint C003 set", "C003  NOP", "C000  3E 42 00 00 18 FE", "Unknown command foo"]);
+    }
+}
@@ -0,0 +1,171 @@
+use std::io;
+use std::io::prelude::*;
+use std::fs::File;
+use std::collections::HashMap;
+
+// http://www.z80.info/decoding.htm, adapted to the GameBoy instruction set
+const R: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const RP: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const RP2: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CC: [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU: [&str; 8] = ["ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP "];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+
+// Labels from a .sym file as written by rgbds/wla: "BB:AAAA name" per line,
+// ';' starts a comment. Banks are ignored, the first label for an address wins.
+pub struct SymbolTable {
+    symbols: HashMap<u16, String>,
+}
+
+impl Default for SymbolTable {
+    fn default() -> SymbolTable {
+        SymbolTable::new()
+    }
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable {
+            symbols: HashMap::new(),
+        }
+    }
+
+    pub fn parse(text: &str) -> SymbolTable {
+        let mut table = SymbolTable::new();
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            let mut parts = line.split_whitespace();
+            let (location, name) = match (parts.next(), parts.next()) {
+                (Some(l), Some(n)) => (l, n),
+                _ => continue,
+            };
+            let address = match location.split(':').nth(1) {
+                Some(a) => a,
+                None => continue,
+            };
+            if let Ok(address) = u16::from_str_radix(address, 16) {
+                table.insert(address, name);
+            }
+        }
+        table
+    }
+
+    pub fn load(filename: &str) -> io::Result<SymbolTable> {
+        let mut text = String::new();
+        File::open(filename).and_then(|mut f| f.read_to_string(&mut text))?;
+        Ok(SymbolTable::parse(&text))
+    }
+
+    pub fn insert(&mut self, address: u16, name: &str) {
+        self.symbols.entry(address).or_insert(name.to_string());
+    }
+
+    pub fn lookup(&self, address: u16) -> Option<&str> {
+        self.symbols.get(&address).map(|s| &s[..])
+    }
+}
+
+fn address(value: u16, symbols: Option<&SymbolTable>) -> String {
+    match symbols.and_then(|s| s.lookup(value)) {
+        Some(name) => name.to_string(),
+        None => format!("${:04X}", value),
+    }
+}
+
+// Disassembles the instruction in `bytes` (at least 3 bytes, fewer near the end
+// of memory) located at `pc`. Returns the text and the instruction length.
+pub fn disassemble(bytes: &[u8], pc: u16, symbols: Option<&SymbolTable>) -> (String, u16) {
+    let byte = |i: usize| -> u8 { if i < bytes.len() { bytes[i] } else { 0 } };
+    let op = byte(0);
+    let n = byte(1);
+    let nn = (byte(1) as u16) | ((byte(2) as u16) << 8);
+    let rel = pc.wrapping_add(2).wrapping_add(n as i8 as u16);
+
+    let x = (op >> 6) as usize;
+    let y = ((op >> 3) & 0x07) as usize;
+    let z = (op & 0x07) as usize;
+    let p = y >> 1;
+    let q = y & 1;
+
+    let (text, len) = match (x, z) {
+        (0, 0) => match y {
+            0 => ("NOP".to_string(), 1),
+            1 => (format!("LD ({}),SP", address(nn, symbols)), 3),
+            2 => ("STOP".to_string(), 2),
+            3 => (format!("JR {}", address(rel, symbols)), 2),
+            _ => (format!("JR {},{}", CC[y - 4], address(rel, symbols)), 2),
+        },
+        (0, 1) => if q == 0 {
+            (format!("LD {},${:04X}", RP[p], nn), 3)
+        } else {
+            (format!("ADD HL,{}", RP[p]), 1)
+        },
+        (0, 2) => {
+            let mem = ["(BC)", "(DE)", "(HL+)", "(HL-)"][p];
+            if q == 0 { (format!("LD {},A", mem), 1) } else { (format!("LD A,{}", mem), 1) }
+        }
+        (0, 3) => (format!("{} {}", if q == 0 { "INC" } else { "DEC" }, RP[p]), 1),
+        (0, 4) => (format!("INC {}", R[y]), 1),
+        (0, 5) => (format!("DEC {}", R[y]), 1),
+        (0, 6) => (format!("LD {},${:02X}", R[y], n), 2),
+        (0, _) => (["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"][y].to_string(), 1),
+        (1, 6) if y == 6 => ("HALT".to_string(), 1),
+        (1, _) => (format!("LD {},{}", R[y], R[z]), 1),
+        (2, _) => (format!("{}{}", ALU[y], R[z]), 1),
+        (3, 0) => match y {
+            0 ..= 3 => (format!("RET {}", CC[y]), 1),
+            4 => (format!("LDH ({}),A", address(0xff00 | n as u16, symbols)), 2),
+            5 => (format!("ADD SP,{}", n as i8), 2),
+            6 => (format!("LDH A,({})", address(0xff00 | n as u16, symbols)), 2),
+            _ => (format!("LD HL,SP{:+}", n as i8), 2),
+        },
+        (3, 1) => if q == 0 {
+            (format!("POP {}", RP2[p]), 1)
+        } else {
+            (["RET", "RETI", "JP HL", "LD SP,HL"][p].to_string(), 1)
+        },
+        (3, 2) => match y {
+            0 ..= 3 => (format!("JP {},{}", CC[y], address(nn, symbols)), 3),
+            4 => ("LD (C),A".to_string(), 1),
+            5 => (format!("LD ({}),A", address(nn, symbols)), 3),
+            6 => ("LD A,(C)".to_string(), 1),
+            _ => (format!("LD A,({})", address(nn, symbols)), 3),
+        },
+        (3, 3) => match y {
+            0 => (format!("JP {}", address(nn, symbols)), 3),
+            1 => {
+                let cb = n as usize;
+                let (cx, cy, cz) = (cb >> 6, (cb >> 3) & 0x07, cb & 0x07);
+                match cx {
+                    0 => (format!("{} {}", ROT[cy], R[cz]), 2),
+                    1 => (format!("BIT {},{}", cy, R[cz]), 2),
+                    2 => (format!("RES {},{}", cy, R[cz]), 2),
+                    _ => (format!("SET {},{}", cy, R[cz]), 2),
+                }
+            }
+            6 => ("DI".to_string(), 1),
+            7 => ("EI".to_string(), 1),
+            _ => (format!("DB ${:02X}", op), 1),
+        },
+        (3, 4) if y < 4 => (format!("CALL {},{}", CC[y], address(nn, symbols)), 3),
+        (3, 5) if q == 0 => (format!("PUSH {}", RP2[p]), 1),
+        (3, 5) if p == 0 => (format!("CALL {}", address(nn, symbols)), 3),
+        (3, 6) => (format!("{}${:02X}", ALU[y], n), 2),
+        (3, 7) => (format!("RST ${:02X}", y * 8), 1),
+        _ => (format!("DB ${:02X}", op), 1),
+    };
+    (text, len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn call_targets_show_their_label() {
+        let symbols = SymbolTable::parse("00:0150 WaitVBlank\n00:0200 Main ; entry\n");
+        assert_eq!(disassemble(&[0xcd, 0x50, 0x01], 0x100, Some(&symbols)), ("CALL WaitVBlank".to_string(), 3));
+        assert_eq!(disassemble(&[0xc3, 0x00, 0x02], 0x100, Some(&symbols)), ("JP Main".to_string(), 3));
+        assert_eq!(disassemble(&[0xcd, 0x60, 0x01], 0x100, Some(&symbols)).0, "CALL $0160");
+    }
+}
@@ -0,0 +1,75 @@
+use gpu::{FrameSink, SCREEN_WIDTH, SCREEN_HEIGHT};
+
+use sdl2::render::Canvas;
+use sdl2::pixels::Color;
+use sdl2::rect::Point;
+use sdl2::rect::Rect;
+use sdl2::video::{FullscreenType, Window};
+
+use std::cmp;
+
+// Largest integer multiple of the screen that fits the output, centered.
+// Pixel perfect at the cost of black bars.
+pub fn scaled_viewport(width: u32, height: u32) -> Rect {
+    let scale = cmp::max(cmp::min(width / SCREEN_WIDTH, height / SCREEN_HEIGHT), 1);
+    let w = SCREEN_WIDTH * scale;
+    let h = SCREEN_HEIGHT * scale;
+    Rect::new((width as i32 - w as i32) / 2, (height as i32 - h as i32) / 2, w, h)
+}
+
+// Presents frames in the SDL window
+pub struct SdlDisplay {
+    canvas: Canvas<Window>,
+}
+
+impl SdlDisplay {
+    pub fn new(canvas: Canvas<Window>) -> SdlDisplay {
+        SdlDisplay {
+            canvas,
+        }
+    }
+}
+
+impl FrameSink for SdlDisplay {
+    fn present(&mut self, frame: &[u8; 160 * 144 * 3]) {
+        let (width, height) = self.canvas.output_size().unwrap_or((SCREEN_WIDTH, SCREEN_HEIGHT));
+        let viewport = scaled_viewport(width, height);
+        let scale = viewport.width() / SCREEN_WIDTH;
+
+        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
+        self.canvas.clear();
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                let i = (x + (y * SCREEN_WIDTH)) as usize * 3;
+                self.canvas.set_draw_color(Color::RGB(frame[i], frame[i + 1], frame[i + 2]));
+                if scale == 1 {
+                    let _ = self.canvas.draw_point(Point::new(viewport.x() + x as i32, viewport.y() + y as i32));
+                } else {
+                    let _ = self.canvas.fill_rect(Rect::new(viewport.x() + (x * scale) as i32, viewport.y() + (y * scale) as i32, scale, scale));
+                }
+            }
+        }
+        self.canvas.present();
+    }
+
+    fn toggle_fullscreen(&mut self) {
+        let window = self.canvas.window_mut();
+        let state = match window.fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+        };
+        let _ = window.set_fullscreen(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scaled_viewport_uses_the_largest_integer_scale() {
+        assert_eq!(scaled_viewport(1920, 1080), Rect::new(400, 36, 1120, 1008));
+        assert_eq!(scaled_viewport(160, 144), Rect::new(0, 0, 160, 144));
+        assert_eq!(scaled_viewport(800, 600), Rect::new(80, 12, 640, 576));
+    }
+}
@@ -1,83 +1,281 @@
-﻿use sdl2::render::Renderer;
-use sdl2::pixels::Color;
-use sdl2::rect::Point;
+﻿use png;
+use state::{StateReader, StateWriter};
+
+use std::cmp;
+use std::io;
+use std::path::Path;
+
+pub const FRAME_CYCLES: u32 = 70224; // 154 scanlines * 456 cycles
+
+pub const SCREEN_WIDTH: u32 = 160;
+pub const SCREEN_HEIGHT: u32 = 144;
+
+// Bytes written by save_state: VRAM, OAM, the LCDC flags, the registers and
+// counters, then both frame buffers
+pub const STATE_SIZE: usize = 0x2000 + 0x100 + 8 + 19 + 2 * (SCREEN_WIDTH * SCREEN_HEIGHT) as usize + 1;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PixelFormat {
+    Indexed,    // Shade index 0-3 per pixel, 0 is the lightest
+    Rgb24,
+    Rgba32,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match *self {
+            PixelFormat::Indexed => 1,
+            PixelFormat::Rgb24 => 3,
+            PixelFormat::Rgba32 => 4,
+        }
+    }
+}
+
+// Receives every presented frame as RGB, keeps the GPU free of any output
+// library. The SDL window is one implementation, see display::SdlDisplay
+pub trait FrameSink {
+    fn present(&mut self, frame: &[u8; 160 * 144 * 3]);
+    fn toggle_fullscreen(&mut self) {}
+}
+
+// Which tile map a map dump shows
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum TileMap {
+    Background,
+    Window,
+}
+
+// Gray level of each shade index, lightest first
+pub const SHADES: [u8; 4] = [255, 192, 96, 0];
+
+// Converts a frame of shade indices (palette output, 0-3) to the requested format
+pub fn convert_frame(indices: &[u8], format: PixelFormat) -> Vec<u8> {
+    let mut out = Vec::with_capacity(indices.len() * format.bytes_per_pixel());
+    for &index in indices {
+        let level = SHADES[(index & 0x03) as usize];
+        match format {
+            PixelFormat::Indexed => out.push(index & 0x03),
+            PixelFormat::Rgb24 => out.extend_from_slice(&[level, level, level]),
+            PixelFormat::Rgba32 => out.extend_from_slice(&[level, level, level, 0xff]),
+        }
+    }
+    out
+}
+
+// Averages two frames per pixel, emulating the slow response of the DMG LCD
+pub fn blend_frames(current: &[u8], previous: &[u8], out: &mut [u8]) {
+    for i in 0..out.len() {
+        out[i] = ((current[i] as u16 + previous[i] as u16) / 2) as u8;
+    }
+}
+
+// Converts a CGB 15 bit BGR color to 8 bit RGB. Channels are expanded by
+// repeating their top bits so 0x1f maps to 0xff. The optional correction
+// mixes the channels like the washed out CGB screen does.
+// https://byuu.net/video/color-emulation
+pub fn cgb_to_rgb(color: u16, correct: bool) -> (u8, u8, u8) {
+    let r = (color & 0x1f) as u32;
+    let g = ((color >> 5) & 0x1f) as u32;
+    let b = ((color >> 10) & 0x1f) as u32;
+    if correct {
+        let cr = cmp::min(r * 26 + g * 4 + b * 2, 960) >> 2;
+        let cg = cmp::min(g * 24 + b * 8, 960) >> 2;
+        let cb = cmp::min(r * 6 + g * 4 + b * 22, 960) >> 2;
+        (cr as u8, cg as u8, cb as u8)
+    } else {
+        (((r << 3) | (r >> 2)) as u8, ((g << 3) | (g >> 2)) as u8, ((b << 3) | (b >> 2)) as u8)
+    }
+}
+
+// Spreads the 8 bits of a byte onto the even bits of a u16
+fn spread_bits(value: u8) -> u16 {
+    let mut x = value as u16;
+    x = (x | (x << 4)) & 0x0f0f;
+    x = (x | (x << 2)) & 0x3333;
+    x = (x | (x << 1)) & 0x5555;
+    x
+}
+
+// Decodes a tile row from its two bitplanes into 8 color indices, leftmost first.
+// Interleaving the planes yields all eight 2-bit pixels in a single u16.
+pub fn decode_row(low: u8, high: u8) -> [u8; 8] {
+    let pixels = spread_bits(low) | (spread_bits(high) << 1);
+    let mut row = [0u8; 8];
+    for (x, color) in row.iter_mut().enumerate() {
+        *color = ((pixels >> ((7 - x) * 2)) & 0x03) as u8;
+    }
+    row
+}
+
+// The map wraps around, so does the viewport outline
+fn outline_pixel(rgb: &mut [u8], x: i32, y: i32) {
+    let i = (((y & 0xff) << 8) | (x & 0xff)) as usize * 3;
+    rgb[i] = 0xff;
+    rgb[i + 1] = 0;
+    rgb[i + 2] = 0;
+}
 
 pub struct GPU {
     pub vram: [u8; 0x2000], // Video RAM
     pub oam: [u8; 0x100], // Sprite Attrib Memory
     //pub lcd_control: u8,
     pub switchbg: bool,
+    pub switchobj: bool,
+    pub sprite_size: bool,  // LCDC bit 2, 8x16 sprites when set
     pub bg_map: bool,
     pub bg_tile: bool,
+    pub switchwin: bool,
+    pub window_map: bool,
     pub lcd_on: bool,
     pub scanline: u8,
     pub scroll_x: u8,
     pub scroll_y: u8,
     pub win_x: u8,
     pub win_y: u8,
+    win_line: u8,   // Window row to draw next, only advances on lines showing the window
+    win_triggered: bool, // LY matched WY during this frame
     gpu_mode: u8,
     gpu_ticks: u32,
-    prev_ticks: u32,
+    stat: u8,       // STAT interrupt selects, bits 3-6
+    pub lyc: u8,    // Compared with LY as it changes, see set_lyc
+    stat_line: bool, // The STAT interrupt fires on a rising edge of this line
+    stat_irq: bool,  // STAT interrupt raised, see take_stat_irq
+    pub bgp: u8,  // Raw palette registers as last written
+    pub obp0: u8,
+    pub obp1: u8,
     palette_b: [u8; 4],
     s_palette0: [u8; 4],
     s_palette1: [u8; 4],
     pixel_buffer: [u8; 160 * 144],
+    index_buffer: [u8; 160 * 144], // Palette (0 BGP, 1 OBP0, 2 OBP1) << 2 | color number
+    line_palettes: [[u8; 3]; 144], // BGP, OBP0 and OBP1 as each line was drawn
+    line_colors: [u8; 160], // BG/window color numbers of the current line, for sprite priority
+    prev_frame: [u8; 160 * 144],
+    frame_ready: bool,  // A frame was completed and not yet presented
+    pub frame_blend: bool,
     tiles: [[[u8; 8]; 8]; 384],
-    renderer: Renderer<'static>,
+    sink: Option<Box<dyn FrameSink>>, // None when running headless
 }
 
 impl GPU {
-    pub fn new(render: Renderer<'static>) -> GPU {
+    pub fn new(sink: Box<dyn FrameSink>) -> GPU {
+        GPU::with_sink(Some(sink))
+    }
+
+    // Only renders into the pixel buffer, presenting does nothing
+    pub fn headless() -> GPU {
+        GPU::with_sink(None)
+    }
+
+    fn with_sink(sink: Option<Box<dyn FrameSink>>) -> GPU {
         GPU {
             vram: [0; 0x2000],
             oam: [0; 0x100],
             //lcd_control: 0,
             switchbg: false,
+            switchobj: false,
+            sprite_size: false,
             bg_map: false,
             bg_tile: false,
+            switchwin: false,
+            window_map: false,
             lcd_on: false,
             scanline: 0,
             scroll_x: 0,
             scroll_y: 0,
             win_x: 0,
             win_y: 0,
+            win_line: 0,
+            win_triggered: false,
             gpu_mode: 0,
             gpu_ticks: 0,
-            prev_ticks: 0,
+            stat: 0,
+            lyc: 0,
+            stat_line: false,
+            stat_irq: false,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
             palette_b: [0; 4],
             s_palette0: [0; 4],
             s_palette1: [0; 4],
             pixel_buffer: [0; 160 * 144],
+            index_buffer: [0; 160 * 144],
+            line_palettes: [[0; 3]; 144],
+            line_colors: [0; 160],
+            prev_frame: [0; 160 * 144],
+            frame_ready: false,
+            frame_blend: false,
             tiles: [[[0u8; 8]; 8]; 384],
-            renderer: render,
+            sink,
         }
     }
 
+    // Color numbers of row `y` of `tile`, decoded straight from VRAM
+    fn tile_row(&self, tile: usize, y: usize) -> [u8; 8] {
+        let address = tile * 16 + y * 2;
+        decode_row(self.vram[address], self.vram[address + 1])
+    }
+
+    // Tiles 0-255 live at 0x8000, tiles 256-383 at 0x8800. With LCDC bit 4 clear
+    // the map holds signed indices around 0x9000, i.e. 0-127 select 256-383
+    fn bg_tile_index(&self, value: u8) -> usize {
+        if !self.bg_tile && value < 128 { value as usize + 256 } else { value as usize }
+    }
+
     pub fn render_scanline(&mut self) {
-        let mut map_offset = (if self.bg_map { 0x1c00 } else { 0x1800 });
-        map_offset += (((self.scanline + self.scroll_y) & 255) >> 3);
+        if !self.lcd_on || self.scanline as u32 >= SCREEN_HEIGHT { return; }
+        // A blanked BG reads as color 0 of an all white palette
+        let bgp = if self.switchbg { self.bgp } else { 0 };
+        self.line_palettes[self.scanline as usize] = [bgp, self.obp0, self.obp1];
+        if self.switchbg {
+            self.render_background();
+        } else {
+            // On DMG LCDC bit 0 blanks the BG and the window to white, sprites still show
+            let offset = self.scanline as usize * 160;
+            for i in 0..160 {
+                self.line_colors[i] = 0;
+                self.pixel_buffer[offset + i] = 255;
+                self.index_buffer[offset + i] = 0;
+            }
+        }
+
+        // The window starts on the first line where LY equals WY and then stays
+        // on for the frame, even if WY is changed later
+        if self.scanline == self.win_y { self.win_triggered = true; }
+        if self.switchbg && self.switchwin && self.win_triggered && self.win_x < 166 {
+            self.render_window();
+        }
+        if self.switchobj {
+            self.render_sprites();
+        }
+        // Only writes into the buffer, the frontend presents once per VBlank
+    }
+
+    fn render_background(&mut self) {
+        let bg_y = self.scanline.wrapping_add(self.scroll_y) as usize;
+        let map_offset = (if self.bg_map { 0x1c00 } else { 0x1800 }) + (bg_y >> 3) * 32;
 
-        let mut line_offset = (self.scroll_x >> 3);
+        let mut line_offset = (self.scroll_x >> 3) as usize;
 
-        let mut x = self.scroll_x & 7;
-        let y = (self.scanline + self.scroll_y) & 7;
+        let mut x = (self.scroll_x & 7) as usize;
+        let y = bg_y & 7;
  
-        let mut pixel_offset = self.scanline as u32 * 160;
+        let line_start = self.scanline as usize * 160;
 
-        let mut tile: u32 = self.vram[(map_offset + line_offset) as usize] as u32;
-        tile += (if self.bg_tile && tile < 128 { 256 } else { 0 });
+        let mut row = self.tile_row(self.bg_tile_index(self.vram[map_offset + line_offset]), y);
 
         for i in 0..160 {
-            let color = self.tiles[tile as usize][x as usize][y as usize];
-            self.pixel_buffer[pixel_offset as usize] = self.palette_b[color as usize];
-            pixel_offset += 1;
+            let color = row[x];
+            self.line_colors[i] = color;
+            self.pixel_buffer[line_start + i] = self.palette_b[color as usize];
+            self.index_buffer[line_start + i] = color;
 
             x += 1;
             if x == 8 {
                 x = 0;
                 line_offset = (line_offset + 1) & 31;
-                tile = self.vram[(map_offset + line_offset) as usize] as u32;
-                tile += (if self.bg_tile && tile < 128 { 256 } else { 0 });
+                row = self.tile_row(self.bg_tile_index(self.vram[map_offset + line_offset]), y);
             }
         }
 
@@ -90,81 +288,427 @@ impl GPU {
         //         }
         //     }
         // } 
-        self.draw_framebuffer();
+    }
+
+    // The window starts at screen x WX - 7. With WX < 7 its leftmost columns are
+    // cut off, from WX = 166 on it's hidden.
+    fn render_window(&mut self) {
+        let map_offset = (if self.window_map { 0x1c00 } else { 0x1800 }) + (self.win_line as usize >> 3) * 32;
+        let y = self.win_line as usize & 7;
+        let start = self.win_x as i32 - 7;
+        let line_offset = self.scanline as usize * 160;
+        let mut row = [0u8; 8];
+        let mut fetched = None;
+        for px in cmp::max(start, 0)..SCREEN_WIDTH as i32 {
+            let wx = (px - start) as usize;
+            if fetched != Some(wx >> 3) {
+                row = self.tile_row(self.bg_tile_index(self.vram[map_offset + (wx >> 3)]), y);
+                fetched = Some(wx >> 3);
+            }
+            let color = row[wx & 7];
+            self.line_colors[px as usize] = color;
+            self.pixel_buffer[line_offset + px as usize] = self.palette_b[color as usize];
+            self.index_buffer[line_offset + px as usize] = color;
+        }
+        self.win_line += 1;
+    }
+
+    // OAM positions are offset by (8, 16) so sprites can hang off the top and
+    // left edges, pixels outside the screen are clipped.
+    // http://imrannazar.com/GameBoy-Emulation-in-JavaScript:-Sprites
+    // Only the first 10 sprites in OAM order covering the line are shown. Where
+    // they overlap the lower X wins, then the lower OAM index, so they're drawn
+    // in reverse of that order.
+    fn render_sprites(&mut self) {
+        let line = self.scanline as i32;
+        if line >= SCREEN_HEIGHT as i32 { return; }
+        let height = if self.sprite_size { 16 } else { 8 };
+        let mut visible: Vec<usize> = (0..40).filter(|&i| {
+            let y = self.oam[i * 4] as i32 - 16;
+            line >= y && line < y + height
+        }).take(10).collect();
+        visible.sort_by_key(|&i| (self.oam[i * 4 + 1], i));
+
+        for &i in visible.iter().rev() {
+            let y = self.oam[i * 4] as i32 - 16;
+            let x = self.oam[i * 4 + 1] as i32 - 8;
+            let attrs = self.oam[i * 4 + 3];
+
+            // 8x16 sprites ignore bit 0 of the tile index and stack the next tile
+            // below, flipping Y swaps the two
+            let row = if (attrs & 0x40) != 0 { height - 1 - (line - y) } else { line - y };
+            let tile = if self.sprite_size {
+                (self.oam[i * 4 + 2] & 0xfe) as usize + (row >> 3) as usize
+            } else {
+                self.oam[i * 4 + 2] as usize
+            };
+            let pixels = self.tile_row(tile, (row & 7) as usize);
+            let (palette, palette_id) = if (attrs & 0x10) != 0 { (self.s_palette1, 2) } else { (self.s_palette0, 1) };
+            for col in 0..8 {
+                let px = x + col;
+                if px < 0 || px >= SCREEN_WIDTH as i32 { continue; }
+                let tx = if (attrs & 0x20) != 0 { 7 - col } else { col };
+                let color = pixels[tx as usize];
+                // Color 0 is transparent for sprites. With attribute bit 7 set the
+                // sprite only shows through BG color 0
+                if color != 0 && ((attrs & 0x80) == 0 || self.line_colors[px as usize] == 0) {
+                    let i = (line * SCREEN_WIDTH as i32 + px) as usize;
+                    self.pixel_buffer[i] = palette[color as usize];
+                    self.index_buffer[i] = (palette_id << 2) | color;
+                }
+            }
+        }
     }
 
     pub fn draw_framebuffer(&mut self) {
-        self.renderer.set_draw_color(Color::RGB(0, 0, 0));
-        self.renderer.clear();
-        for y in 0..144 {
-            for x in 0..160 {
-                let color = self.pixel_buffer[(x + (y * 160)) as usize];
-                self.renderer.set_draw_color(Color::RGB(color, color, color));
-                self.renderer.draw_point(Point::new(x, y));
+        self.frame_ready = false;
+        let buffer = self.output_frame();
+        self.draw_buffer(&buffer);
+    }
+
+    // The frame as it should be presented, after optional post-processing
+    pub fn output_frame(&mut self) -> [u8; 160 * 144] {
+        let mut frame = self.pixel_buffer;
+        if self.frame_blend {
+            blend_frames(&self.pixel_buffer, &self.prev_frame, &mut frame);
+        }
+        self.prev_frame = self.pixel_buffer;
+        frame
+    }
+
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.pixel_buffer
+    }
+
+    // Set at VBlank, once the framebuffer holds a complete frame. Frontends not
+    // going through draw_framebuffer clear it when they have consumed the frame
+    pub fn frame_ready(&self) -> bool {
+        self.frame_ready
+    }
+
+    pub fn clear_frame_ready(&mut self) {
+        self.frame_ready = false;
+    }
+
+    pub fn pixel_buffer(&self) -> [u8; 160 * 144] {
+        self.pixel_buffer
+    }
+
+    // The frame before palette lookup, which palette and color number made each
+    // pixel. pixel_buffer holds the shades as resolved when each line was drawn
+    pub fn index_buffer(&self) -> &[u8] {
+        &self.index_buffer
+    }
+
+    // Shade index of every pixel, index_buffer looked up in the palettes of its line
+    fn shade_indices(&self) -> Vec<u8> {
+        self.index_buffer.iter().enumerate().map(|(i, &index)| {
+            let palette = self.line_palettes[i / SCREEN_WIDTH as usize][(index >> 2) as usize];
+            (palette >> ((index & 0x03) * 2)) & 0x03
+        }).collect()
+    }
+
+    pub fn frame_as(&self, format: PixelFormat) -> Vec<u8> {
+        convert_frame(&self.shade_indices(), format)
+    }
+
+    // LY as read through 0xff44. Line 153 only shows as 153 for its first 4
+    // cycles, LY already reads 0 for the rest of it
+    pub fn ly(&self) -> u8 {
+        if self.scanline == 153 && self.gpu_ticks >= 4 { 0 } else { self.scanline }
+    }
+
+    // Puts the PPU at the start of `mode` on `scanline`, for exercising STAT
+    // handling without running up to that point. Debug builds only
+    #[cfg(debug_assertions)]
+    pub fn force_mode(&mut self, mode: u8, scanline: u8) {
+        self.gpu_mode = mode & 0x03;
+        self.scanline = scanline;
+        self.gpu_ticks = 0;
+    }
+
+    pub fn mode(&self) -> u8 {
+        self.gpu_mode
+    }
+
+    // http://gbdev.gg8.se/wiki/articles/Video_Display#FF41_-_STAT_-_LCDC_Status_.28R.2FW.29
+    pub fn read_stat(&self) -> u8 {
+        0x80 | self.stat | (if self.coincidence() { 0x04 } else { 0 }) | self.gpu_mode
+    }
+
+    fn coincidence(&self) -> bool {
+        self.lcd_on && self.ly() == self.lyc
+    }
+
+    // All enabled sources are ORed into one line, so a source becoming active
+    // while another one already holds the line doesn't interrupt again
+    fn update_stat(&mut self) {
+        let line = self.lcd_on && (((self.stat & 0x08) != 0 && self.gpu_mode == 0) ||
+                                   ((self.stat & 0x10) != 0 && self.gpu_mode == 1) ||
+                                   ((self.stat & 0x20) != 0 && self.gpu_mode == 2) ||
+                                   ((self.stat & 0x40) != 0 && self.coincidence()));
+        if line && !self.stat_line {
+            self.stat_irq = true;
+        }
+        self.stat_line = line;
+    }
+
+    // Writes take effect on the STAT line right away, so setting LYC to the
+    // current line interrupts without waiting for the next scanline
+    pub fn set_lyc(&mut self, value: u8) {
+        self.lyc = value;
+        self.update_stat();
+    }
+
+    pub fn set_stat(&mut self, value: u8) {
+        self.stat = value & 0x78;
+        self.update_stat();
+    }
+
+    // True once after the STAT interrupt was raised
+    pub fn take_stat_irq(&mut self) -> bool {
+        let irq = self.stat_irq;
+        self.stat_irq = false;
+        irq
+    }
+
+    pub fn draw_buffer(&mut self, buffer: &[u8]) {
+        if let Some(ref mut sink) = self.sink {
+            // Blending and the overlay leave gray levels in between the shades
+            let mut rgb = [0u8; 160 * 144 * 3];
+            for (i, &level) in buffer.iter().enumerate() {
+                rgb[i * 3..i * 3 + 3].copy_from_slice(&[level, level, level]);
             }
+            sink.present(&rgb);
+        }
+    }
+
+    // Frontend settings like frame_blend aren't saved, the tile cache is rebuilt
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.vram);
+        w.bytes(&self.oam);
+        for &flag in &[self.switchbg, self.switchobj, self.sprite_size, self.bg_map,
+                       self.bg_tile, self.switchwin, self.window_map, self.lcd_on] {
+            w.bool(flag);
+        }
+        w.u8(self.scanline);
+        w.u8(self.scroll_x);
+        w.u8(self.scroll_y);
+        w.u8(self.win_x);
+        w.u8(self.win_y);
+        w.u8(self.win_line);
+        w.bool(self.win_triggered);
+        w.u8(self.gpu_mode);
+        w.u32(self.gpu_ticks);
+        w.u8(self.stat);
+        w.u8(self.lyc);
+        w.bool(self.stat_line);
+        w.bool(self.stat_irq);
+        w.u8(self.bgp);
+        w.u8(self.obp0);
+        w.u8(self.obp1);
+        w.bytes(&self.pixel_buffer);
+        w.bytes(&self.index_buffer);
+        w.bool(self.frame_ready);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) {
+        r.bytes(&mut self.vram);
+        r.bytes(&mut self.oam);
+        self.switchbg = r.bool();
+        self.switchobj = r.bool();
+        self.sprite_size = r.bool();
+        self.bg_map = r.bool();
+        self.bg_tile = r.bool();
+        self.switchwin = r.bool();
+        self.window_map = r.bool();
+        self.lcd_on = r.bool();
+        self.scanline = r.u8();
+        self.scroll_x = r.u8();
+        self.scroll_y = r.u8();
+        self.win_x = r.u8();
+        self.win_y = r.u8();
+        self.win_line = r.u8();
+        self.win_triggered = r.bool();
+        self.gpu_mode = r.u8();
+        self.gpu_ticks = r.u32();
+        self.stat = r.u8();
+        self.lyc = r.u8();
+        self.stat_line = r.bool();
+        self.stat_irq = r.bool();
+        let (bgp, obp0, obp1) = (r.u8(), r.u8(), r.u8());
+        self.u_palette_b(bgp);
+        self.u_s_palette0(obp0);
+        self.u_s_palette1(obp1);
+        r.bytes(&mut self.pixel_buffer);
+        r.bytes(&mut self.index_buffer);
+        self.line_palettes = [[bgp, obp0, obp1]; 144];
+        self.frame_ready = r.bool();
+        self.prev_frame = self.pixel_buffer;
+        self.rebuild_tiles();
+    }
+
+    // Clears VRAM, OAM and the PPU timing, registers are set by Memory::put_initial
+    pub fn reset(&mut self) {
+        self.vram = [0; 0x2000];
+        self.oam = [0; 0x100];
+        self.tiles = [[[0u8; 8]; 8]; 384];
+        self.scanline = 0;
+        self.win_line = 0;
+        self.win_triggered = false;
+        self.gpu_mode = 0;
+        self.gpu_ticks = 0;
+        self.stat = 0;
+        self.lyc = 0;
+        self.stat_line = false;
+        self.stat_irq = false;
+        self.pixel_buffer = [0; 160 * 144];
+        self.index_buffer = [0; 160 * 144];
+        self.line_palettes = [[0; 3]; 144];
+        self.prev_frame = [0; 160 * 144];
+        self.frame_ready = false;
+    }
+
+    pub fn set_lcd_on(&mut self, on: bool) {
+        if self.lcd_on && !on {
+            self.scanline = 0;
+            self.gpu_mode = 0;
+            self.gpu_ticks = 0;
+            self.win_line = 0;
+            self.win_triggered = false;
+            // A disabled LCD shows a blank white screen
+            self.pixel_buffer = [255; 160 * 144];
+            self.index_buffer = [0; 160 * 144];
+            self.line_palettes = [[0; 3]; 144];
+        }
+        self.lcd_on = on;
+    }
+
+    pub fn toggle_fullscreen(&mut self) {
+        if let Some(ref mut sink) = self.sink {
+            sink.toggle_fullscreen();
         }
-        self.renderer.present();
     }
 
     // http://imrannazar.com/GameBoy-Emulation-in-JavaScript:-Graphics
-    pub fn update_tile(&mut self, address: u16, value: u8) {
-        let addr = (address & 0x1ffe);
+    pub fn update_tile(&mut self, address: u16, _value: u8) {
+        let addr = address & 0x1ffe;
 
         let tile = (addr >> 4) & 511;
         let y = (addr >> 1) & 7;
 
-        let mut bit = 0 as u8;
+        let row = decode_row(self.vram[addr as usize], self.vram[(addr + 1) as usize]);
+        for (x, &color) in row.iter().enumerate() {
+            self.tiles[tile as usize][x][y as usize] = color;
+        }
+    }
+
+    // For callers writing `vram` directly instead of through Memory::write_byte,
+    // re-decodes the tile row holding `address`. Tile map writes need nothing
+    pub fn invalidate_tile(&mut self, address: u16) {
+        if (0x8000..0x9800).contains(&address) {
+            let value = self.vram[address as usize - 0x8000];
+            self.update_tile(address, value);
+        }
+    }
+
+    // The full 256x256 map selected for `which` as RGB, decoded with the current
+    // tile addressing and BG palette. The part shown on screen is outlined in red
+    pub fn render_map(&self, which: TileMap) -> Vec<u8> {
+        let map_base = match which {
+            TileMap::Background => if self.bg_map { 0x1c00 } else { 0x1800 },
+            TileMap::Window => if self.window_map { 0x1c00 } else { 0x1800 },
+        };
+        let mut shades = vec![0u8; 256 * 256];
+        for y in 0..256 {
+            for x in 0..256 {
+                let tile = self.bg_tile_index(self.vram[map_base + (y >> 3) * 32 + (x >> 3)]);
+                shades[y * 256 + x] = (self.bgp >> (self.tiles[tile][x & 7][y & 7] * 2)) & 0x03;
+            }
+        }
+        let mut rgb = convert_frame(&shades, PixelFormat::Rgb24);
+
+        let (x, y, width, height) = match which {
+            TileMap::Background => (self.scroll_x as i32, self.scroll_y as i32, SCREEN_WIDTH as i32, SCREEN_HEIGHT as i32),
+            TileMap::Window => {
+                let start = self.win_x as i32 - 7;
+                (cmp::max(-start, 0), 0, SCREEN_WIDTH as i32 - cmp::max(start, 0), SCREEN_HEIGHT as i32 - self.win_y as i32)
+            }
+        };
+        if width > 0 && height > 0 {
+            for i in 0..width {
+                outline_pixel(&mut rgb, x + i, y);
+                outline_pixel(&mut rgb, x + i, y + height - 1);
+            }
+            for i in 0..height {
+                outline_pixel(&mut rgb, x, y + i);
+                outline_pixel(&mut rgb, x + width - 1, y + i);
+            }
+        }
+        rgb
+    }
+
+    pub fn dump_bg_map_png<P: AsRef<Path>>(&self, which: TileMap, path: P) -> io::Result<()> {
+        png::write_rgb(path, 256, 256, &self.render_map(which))
+    }
 
-        for x in 0..8 {
-            bit = (1 << (7 - x as u8));
-            self.tiles[tile as usize][x as usize][y as usize] = ((if (self.vram[addr as usize] & bit) != 0 { 1 } else { 0 }) + 
-                                        (if (self.vram[(addr + 1) as usize] & bit) != 0 { 2 } else { 0 }));
+    // Regenerates the whole decoded tile cache from VRAM, e.g. after VRAM was
+    // restored without going through write_byte
+    pub fn rebuild_tiles(&mut self) {
+        for address in (0x8000..0x9800).filter(|a| a % 2 == 0) {
+            let value = self.vram[address as usize - 0x8000];
+            self.update_tile(address, value);
         }
     }
 
+    // Lines resolve their colors when they are rendered, so a write mid-frame only
+    // affects the lines after it and a write during VBlank the whole next frame
     pub fn u_palette_b(&mut self, value: u8) {
+        self.bgp = value;
         for i in 0..4 {
             self.palette_b[i] = self.get_color(value, i);
         }
     }
 
     pub fn u_s_palette0(&mut self, value: u8) {
+        self.obp0 = value;
         for i in 0..4 {
             self.s_palette0[i] = self.get_color(value, i);
         }
     }
 
     pub fn u_s_palette1(&mut self, value: u8) {
+        self.obp1 = value;
         for i in 0..4 {
             self.s_palette1[i] = self.get_color(value, i);
         }
     }
 
     fn get_color(&mut self, value: u8, i: usize) -> u8 {
-        match (value >> (i * 2)) & 0x03 {
-            0 => 255,
-            1 => 192,
-            2 => 96,
-            _ => 0
-        }
+        SHADES[((value >> (i * 2)) & 0x03) as usize]
     }
 
     // http://imrannazar.com/GameBoy-Emulation-in-JavaScript:-GPU-Timings
     // http://www.codeslinger.co.uk/pages/projects/gameboy/lcd.html
-    pub fn gpu_cycle(&mut self, cputicks: u32, flags: u8, enable: u8) -> bool {
-        self.gpu_ticks += cputicks - self.prev_ticks;
-        
-        self.prev_ticks = cputicks;
+    // Returns true when the PPU enters VBlank, i.e. a frame has been completed
+    pub fn gpu_cycle(&mut self, cycles: u32) -> bool {
+        // With the LCD off the PPU is stopped at LY 0 and raises no interrupts
+        if !self.lcd_on { return false; }
+        self.gpu_ticks += cycles;
 
-        let mut flagupdate = false;
+        let mut vblank = false;
 
         match self.gpu_mode {
             0 => { 
                 if self.gpu_ticks >= 204 {
                     self.scanline += 1;
-                    if self.scanline == 143 {
-                        if (enable & flags) != 0 { flagupdate = true; }
+                    // Lines 0-143 are drawn, VBlank covers lines 144-153
+                    if self.scanline == 144 {
+                        vblank = true;
+                        self.frame_ready = true;
                         self.gpu_mode = 1;
+                        self.win_line = 0;
+                        self.win_triggered = false;
                     }
                     else {
                         self.gpu_mode = 2;
@@ -191,12 +735,354 @@ impl GPU {
             3 => {
                 if self.gpu_ticks >= 172 {
                     self.gpu_mode = 0;
-                    //self.render_scanline();
+                    // The line is drawn with the registers as they are at the end
+                    // of mode 3, so mid-frame scroll and palette writes show
+                    self.render_scanline();
                     self.gpu_ticks -= 172;
                 }
             }
             _ => { panic!("Unknown gpu mode!") }
         }
-        flagupdate
+        self.update_stat();
+        vblank
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn decode_row_matches_the_bit_loop() {
+        for low in 0..256 {
+            for high in 0..256 {
+                let row = decode_row(low as u8, high as u8);
+                for (x, &decoded) in row.iter().enumerate() {
+                    let bit = 1 << (7 - x);
+                    let color = (if (low & bit) != 0 { 1 } else { 0 }) + (if (high & bit) != 0 { 2 } else { 0 });
+                    assert_eq!(decoded, color, "low {:02x} high {:02x} x {}", low, high, x);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn blend_frames_averages_each_pixel() {
+        let current = [0xff, 0x00, 0xaa, 0x55];
+        let previous = [0x00, 0x00, 0x55, 0x55];
+        let mut out = [0; 4];
+        blend_frames(&current, &previous, &mut out);
+        assert_eq!(out, [0x7f, 0x00, 0x7f, 0x55]);
+    }
+
+    #[test]
+    fn cgb_colors_expand_to_the_full_range() {
+        assert_eq!(cgb_to_rgb(0x0000, false), (0x00, 0x00, 0x00));
+        assert_eq!(cgb_to_rgb(0x7fff, false), (0xff, 0xff, 0xff));
+        assert_eq!(cgb_to_rgb(0x001f, false), (0xff, 0x00, 0x00));
+        assert_eq!(cgb_to_rgb(0x0210, false), (0x84, 0x84, 0x00));
+        assert_eq!(cgb_to_rgb(0x0000, true), (0x00, 0x00, 0x00));
+        assert_eq!(cgb_to_rgb(0x7fff, true), (0xf0, 0xf0, 0xf0));
+        assert_eq!(cgb_to_rgb(0x001f, true), (0xc9, 0x00, 0x2e));
+    }
+
+    // Renders a frame with only sprite 0 on a blank BG. Its tile has color 3 in
+    // the bottom-right quarter and is transparent elsewhere. Returns the
+    // screen pixels the sprite covered
+    fn sprite_pixels(y: u8, x: u8) -> Vec<(usize, usize)> {
+        let mut gpu = GPU::headless();
+        gpu.lcd_on = true;
+        gpu.switchobj = true;
+        for row in 4..8 {
+            gpu.vram[16 + row * 2] = 0x0f;
+            gpu.vram[16 + row * 2 + 1] = 0x0f;
+        }
+        gpu.oam[0..4].copy_from_slice(&[y, x, 1, 0]);
+        for line in 0..SCREEN_HEIGHT {
+            gpu.scanline = line as u8;
+            gpu.render_scanline();
+        }
+        let mut covered = Vec::new();
+        for (i, &index) in gpu.index_buffer().iter().enumerate() {
+            if index != 0 {
+                covered.push((i % 160, i / 160));
+            }
+        }
+        covered
+    }
+
+    #[test]
+    fn sprites_are_offset_by_16_and_8() {
+        let quarter: Vec<(usize, usize)> = (0..16).map(|i| (4 + i % 4, 4 + i / 4)).collect();
+        assert_eq!(sprite_pixels(16, 8), quarter);
+        let clipped: Vec<(usize, usize)> = (0..16).map(|i| (i % 4, i / 4)).collect();
+        assert_eq!(sprite_pixels(12, 4), clipped);
+        assert!(sprite_pixels(8, 0).is_empty());
+    }
+
+    #[test]
+    fn ly_counts_every_line_in_order() {
+        let mut gpu = GPU::headless();
+        gpu.lcd_on = true;
+        gpu.gpu_mode = 2;
+        let mut changes = vec![(0, 0)];
+        let mut cycles = 0;
+        while cycles < FRAME_CYCLES {
+            gpu.gpu_cycle(4);
+            cycles += 4;
+            if gpu.ly() != changes[changes.len() - 1].0 {
+                changes.push((gpu.ly(), cycles));
+            }
+        }
+        let mut expected: Vec<(u8, u32)> = (0..154).map(|line| (line as u8, line * 456)).collect();
+        expected.push((0, 153 * 456 + 4));
+        assert_eq!(changes, expected);
+    }
+
+    #[test]
+    fn frame_as_looks_indices_up_in_the_line_palettes() {
+        let mut gpu = GPU::headless();
+        gpu.line_palettes[0] = [0xe4, 0x1b, 0xd2];
+        // BG color 2, OBP0 color 1, OBP1 color 3, BG color 0
+        gpu.index_buffer[0..4].copy_from_slice(&[0x02, 0x05, 0x0b, 0x00]);
+        gpu.index_buffer[160] = 0x03; // Line 1 was drawn with the BG off
+
+        let indexed = gpu.frame_as(PixelFormat::Indexed);
+        assert_eq!(indexed.len(), 160 * 144);
+        assert_eq!(&indexed[0..4], &[2, 2, 3, 0]);
+        assert_eq!(indexed[160], 0);
+
+        let rgb = gpu.frame_as(PixelFormat::Rgb24);
+        assert_eq!(rgb.len(), 160 * 144 * 3);
+        assert_eq!(&rgb[0..12], &[96, 96, 96, 96, 96, 96, 0, 0, 0, 255, 255, 255]);
+
+        let rgba = gpu.frame_as(PixelFormat::Rgba32);
+        assert_eq!(rgba.len(), 160 * 144 * 4);
+        assert_eq!(&rgba[0..12], &[96, 96, 96, 0xff, 96, 96, 96, 0xff, 0, 0, 0, 0xff]);
+    }
+
+    #[test]
+    fn line_153_reads_0_after_4_cycles() {
+        let mut gpu = GPU::headless();
+        gpu.lcd_on = true;
+        gpu.gpu_mode = 1;
+        gpu.scanline = 153;
+        assert_eq!(gpu.ly(), 153);
+        gpu.gpu_cycle(2);
+        assert_eq!(gpu.ly(), 153);
+        gpu.gpu_cycle(2);
+        assert_eq!(gpu.ly(), 0);
+        gpu.gpu_cycle(448);
+        assert_eq!((gpu.ly(), gpu.scanline), (0, 153));
+        gpu.gpu_cycle(4);
+        assert_eq!((gpu.ly(), gpu.scanline, gpu.gpu_mode), (0, 0, 2));
+    }
+
+    #[test]
+    fn bgp_writes_only_affect_later_lines() {
+        let mut gpu = GPU::headless();
+        gpu.lcd_on = true;
+        gpu.switchbg = true;
+        gpu.gpu_mode = 2;
+        gpu.u_palette_b(0x00);
+        while gpu.scanline < 72 {
+            gpu.gpu_cycle(4);
+        }
+        gpu.u_palette_b(0xff);
+        while !gpu.gpu_cycle(4) {}
+
+        let frame = gpu.pixel_buffer();
+        assert!(frame[..72 * 160].iter().all(|&level| level == 255));
+        assert!(frame[72 * 160..].iter().all(|&level| level == 0));
+        let indexed = gpu.frame_as(PixelFormat::Indexed);
+        assert!(indexed[..72 * 160].iter().all(|&index| index == 0));
+        assert!(indexed[72 * 160..].iter().all(|&index| index == 3));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn force_mode_shows_in_stat() {
+        let mut gpu = GPU::headless();
+        gpu.lcd_on = true;
+        gpu.lyc = 50;
+        gpu.force_mode(0, 50);
+        assert_eq!(gpu.ly(), 50);
+        assert_eq!(gpu.read_stat(), 0x84);
+        gpu.force_mode(3, 51);
+        assert_eq!(gpu.read_stat(), 0x83);
+    }
+
+    #[test]
+    fn any_tile_map_byte_renders() {
+        for &signed in &[false, true] {
+            for &byte in &[0x00, 0x7f, 0x80, 0xff] {
+                let mut gpu = GPU::headless();
+                gpu.lcd_on = true;
+                gpu.switchbg = true;
+                gpu.switchwin = true;
+                gpu.switchobj = true;
+                gpu.window_map = true;
+                gpu.bg_tile = !signed;
+                for i in 0x1800..0x2000 {
+                    gpu.vram[i] = byte;
+                }
+                for i in 0..40 {
+                    gpu.oam[i * 4..i * 4 + 4].copy_from_slice(&[16 + i as u8, 8 + i as u8, byte, 0]);
+                }
+                assert!(gpu.bg_tile_index(byte) < 384);
+                for line in 0..SCREEN_HEIGHT {
+                    gpu.scanline = line as u8;
+                    gpu.render_scanline();
+                }
+                gpu.render_map(TileMap::Background);
+                gpu.render_map(TileMap::Window);
+            }
+        }
+    }
+
+    #[test]
+    fn rebuild_tiles_matches_update_tile() {
+        let mut rebuilt = GPU::headless();
+        let mut updated = GPU::headless();
+        for i in 0..0x1800 {
+            let value = (i * 31 + i / 7) as u8;
+            rebuilt.vram[i] = value;
+            updated.vram[i] = value;
+            updated.update_tile(0x8000 + i as u16, value);
+        }
+        rebuilt.rebuild_tiles();
+        assert!(rebuilt.tiles[..] == updated.tiles[..]);
+        assert!(rebuilt.tiles.iter().any(|tile| *tile != [[0; 8]; 8]));
+    }
+
+    // Draws line 0 with a blank BG and a window whose tiles only have their
+    // last column set. Returns the color numbers of the line
+    fn window_line(wx: u8) -> Vec<u8> {
+        let mut gpu = GPU::headless();
+        gpu.lcd_on = true;
+        gpu.switchbg = true;
+        gpu.switchwin = true;
+        gpu.window_map = true;
+        gpu.bg_tile = true;
+        for i in 0..0x400 {
+            gpu.vram[0x1c00 + i] = 1;
+        }
+        for row in 0..8 {
+            gpu.vram[16 + row * 2] = 0x01;
+            gpu.vram[16 + row * 2 + 1] = 0x01;
+        }
+        gpu.win_x = wx;
+        gpu.render_scanline();
+        gpu.index_buffer()[..160].to_vec()
+    }
+
+    #[test]
+    fn window_edges_follow_wx() {
+        let line = window_line(7);
+        assert!((0..160).all(|x| line[x] == if x % 8 == 7 { 3 } else { 0 }));
+        let line = window_line(0);
+        assert!((0..160).all(|x| line[x] == if x % 8 == 0 { 3 } else { 0 }));
+        assert!(window_line(166).iter().all(|&color| color == 0));
+    }
+
+    #[test]
+    fn dump_bg_map_png_writes_the_whole_map() {
+        let mut gpu = GPU::headless();
+        gpu.bg_tile = true;
+        gpu.u_palette_b(0xe4);
+        for i in 16..32 {
+            gpu.vram[i] = 0xff;
+            gpu.update_tile(0x8000 + i as u16, 0xff);
+        }
+        gpu.vram[0x1800 + 3 * 32 + 2] = 1; // Tile 1 at column 2, row 3
+        gpu.scroll_x = 0x80;
+        gpu.scroll_y = 0x80;
+
+        let rgb = gpu.render_map(TileMap::Background);
+        assert_eq!(rgb.len(), 256 * 256 * 3);
+        let pixel = |x: usize, y: usize| &rgb[(y * 256 + x) * 3..(y * 256 + x) * 3 + 3];
+        assert_eq!(pixel(20, 28), &[0, 0, 0]);
+        assert_eq!(pixel(0, 0), &[255, 255, 255]);
+        assert_eq!(pixel(0x80, 0x80), &[255, 0, 0]);
+
+        let path = env::temp_dir().join("rustboy_bg_map_test.png");
+        gpu.dump_bg_map_png(TileMap::Background, &path).unwrap();
+        let png = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(&png[12..24], b"IHDR\0\0\x01\0\0\0\x01\0");
+    }
+
+    #[test]
+    fn invalidate_tile_picks_up_direct_vram_writes() {
+        let mut gpu = GPU::headless();
+        gpu.bg_tile = true;
+        gpu.u_palette_b(0xe4);
+        gpu.vram[0] = 0xff;
+        gpu.vram[1] = 0xff;
+        gpu.scroll_x = 0x80; // Keep the outline off pixel 0
+        gpu.scroll_y = 0x80;
+        assert_eq!(gpu.render_map(TileMap::Background)[..3], [255, 255, 255]);
+        gpu.invalidate_tile(0x8001);
+        assert_eq!(gpu.tiles[0][0][0], 3);
+        assert_eq!(gpu.render_map(TileMap::Background)[..3], [0, 0, 0]);
+    }
+
+    // Renders a frame with a striped BG and an 8 line window status bar at the
+    // bottom, both scrolled by `scroll`. Returns the color numbers
+    fn status_bar_frame(scroll: u8) -> Vec<u8> {
+        let mut gpu = GPU::headless();
+        gpu.lcd_on = true;
+        gpu.switchbg = true;
+        gpu.switchwin = true;
+        gpu.window_map = true;
+        gpu.bg_tile = true;
+        for row in 0..8 {
+            gpu.vram[16 + row * 2] = 0xf0; // Tile 1, color 1 on the left half
+            gpu.vram[32 + row * 2 + 1] = 0xcc; // Tile 2, color 2 in pairs
+        }
+        for i in 0..0x400 {
+            gpu.vram[0x1800 + i] = if i % 3 == 0 { 1 } else { 0 };
+            gpu.vram[0x1c00 + i] = if i % 2 == 0 { 2 } else { 0 };
+        }
+        gpu.win_x = 7;
+        gpu.win_y = 136;
+        gpu.scroll_x = scroll;
+        gpu.scroll_y = scroll;
+        for line in 0..SCREEN_HEIGHT {
+            gpu.scanline = line as u8;
+            gpu.render_scanline();
+        }
+        gpu.index_buffer().to_vec()
+    }
+
+    #[test]
+    fn window_stays_pinned_while_the_bg_scrolls() {
+        let still = status_bar_frame(0);
+        let scrolled = status_bar_frame(13);
+        let bar = 136 * 160;
+        assert!(still[..bar] != scrolled[..bar]);
+        assert_eq!(&still[bar..], &scrolled[bar..]);
+        assert!(still[bar..].iter().all(|&color| color == 0 || color == 2));
+        assert_eq!(&still[bar..bar + 16], &[2, 2, 0, 0, 2, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0][..]);
+    }
+
+    #[test]
+    fn stat_coincidence_follows_lyc() {
+        let mut gpu = GPU::headless();
+        gpu.lcd_on = true;
+        gpu.gpu_mode = 2;
+        gpu.set_lyc(80);
+        let mut matched = Vec::new();
+        for _ in 0..FRAME_CYCLES / 4 {
+            gpu.gpu_cycle(4);
+            if (gpu.read_stat() & 0x04) != 0 && !matched.contains(&gpu.ly()) {
+                matched.push(gpu.ly());
+            }
+        }
+        assert_eq!(matched, vec![80]);
+        gpu.set_lyc(gpu.ly());
+        assert_eq!(gpu.read_stat() & 0x04, 0x04);
+    }
+}
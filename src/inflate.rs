@@ -0,0 +1,260 @@
+// Minimal DEFLATE decoder (RFC 1951) for compressed ROM files, after zlib's puff.c
+// Only decompression is needed and ROMs are small, so speed is not a concern.
+
+macro_rules! try_opt {
+    ($e:expr) => (match $e { Some(v) => v, None => return None })
+}
+
+const MAX_BITS: usize = 15;
+// The largest valid ROM. Anything inflating past it is refused, e.g. zip bombs
+const MAX_OUTPUT: usize = 8 << 20;
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+                                35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+                                3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+                              257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+                              8193, 12289, 16385, 24577];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+                              7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bitbuf: u32,
+    bitcnt: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn bits(&mut self, need: u32) -> Option<u32> {
+        let mut val = self.bitbuf;
+        while self.bitcnt < need {
+            let byte = match self.data.get(self.pos) { Some(b) => *b as u32, None => return None };
+            self.pos += 1;
+            val |= byte << self.bitcnt;
+            self.bitcnt += 8;
+        }
+        self.bitbuf = val >> need;
+        self.bitcnt -= need;
+        Some(val & ((1 << need) - 1))
+    }
+
+    fn align(&mut self) {
+        self.bitbuf = 0;
+        self.bitcnt = 0;
+    }
+}
+
+// Canonical Huffman code, symbols sorted by code length
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        let mut offsets = [0u16; MAX_BITS + 1];
+        for len in 1..MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, input: &mut BitReader) -> Option<u16> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for len in 1..MAX_BITS + 1 {
+            code |= try_opt!(input.bits(1)) as i32;
+            let count = self.counts[len] as i32;
+            if code - count < first {
+                return self.symbols.get((index + (code - first)) as usize).cloned();
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+        None
+    }
+}
+
+fn fixed_tables() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    for length in &mut lengths[0..144] { *length = 8; }
+    for length in &mut lengths[144..256] { *length = 9; }
+    for length in &mut lengths[256..280] { *length = 7; }
+    for length in &mut lengths[280..288] { *length = 8; }
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_tables(input: &mut BitReader) -> Option<(Huffman, Huffman)> {
+    let nlen = try_opt!(input.bits(5)) as usize + 257;
+    let ndist = try_opt!(input.bits(5)) as usize + 1;
+    let ncode = try_opt!(input.bits(4)) as usize + 4;
+    if nlen > 286 || ndist > 30 { return None; }
+
+    let mut lengths = [0u8; 19];
+    for i in 0..ncode {
+        lengths[CODE_LENGTH_ORDER[i]] = try_opt!(input.bits(3)) as u8;
+    }
+    let lencode = Huffman::new(&lengths);
+
+    let mut lengths = vec![0u8; nlen + ndist];
+    let mut i = 0;
+    while i < nlen + ndist {
+        let symbol = try_opt!(lencode.decode(input));
+        if symbol < 16 {
+            lengths[i] = symbol as u8;
+            i += 1;
+            continue;
+        }
+        let (value, repeat) = match symbol {
+            16 => {
+                if i == 0 { return None; }
+                (lengths[i - 1], 3 + try_opt!(input.bits(2)) as usize)
+            }
+            17 => (0, 3 + try_opt!(input.bits(3)) as usize),
+            _ => (0, 11 + try_opt!(input.bits(7)) as usize),
+        };
+        if i + repeat > nlen + ndist { return None; }
+        for _ in 0..repeat {
+            lengths[i] = value;
+            i += 1;
+        }
+    }
+    Some((Huffman::new(&lengths[..nlen]), Huffman::new(&lengths[nlen..])))
+}
+
+fn codes(input: &mut BitReader, out: &mut Vec<u8>, lencode: &Huffman, distcode: &Huffman) -> Option<()> {
+    loop {
+        let symbol = try_opt!(lencode.decode(input)) as usize;
+        if symbol < 256 {
+            if out.len() == MAX_OUTPUT { return None; }
+            out.push(symbol as u8);
+        } else if symbol == 256 {
+            return Some(());
+        } else {
+            let symbol = symbol - 257;
+            if symbol >= 29 { return None; }
+            let len = LENGTH_BASE[symbol] as usize + try_opt!(input.bits(LENGTH_EXTRA[symbol] as u32)) as usize;
+            let symbol = try_opt!(distcode.decode(input)) as usize;
+            if symbol >= 30 { return None; }
+            let dist = DIST_BASE[symbol] as usize + try_opt!(input.bits(DIST_EXTRA[symbol] as u32)) as usize;
+            if dist > out.len() || out.len() + len > MAX_OUTPUT { return None; }
+            for _ in 0..len {
+                let b = out[out.len() - dist];
+                out.push(b);
+            }
+        }
+    }
+}
+
+// Decompresses a raw DEFLATE stream, None if it is malformed or truncated
+pub fn inflate(data: &[u8]) -> Option<Vec<u8>> {
+    let mut input = BitReader { data, pos: 0, bitbuf: 0, bitcnt: 0 };
+    let mut out = Vec::new();
+    loop {
+        let last = try_opt!(input.bits(1)) == 1;
+        match try_opt!(input.bits(2)) {
+            0 => {
+                input.align();
+                let pos = input.pos;
+                if pos + 4 > data.len() { return None; }
+                let len = data[pos] as usize | (data[pos + 1] as usize) << 8;
+                let nlen = data[pos + 2] as usize | (data[pos + 3] as usize) << 8;
+                if nlen != !len & 0xffff { return None; }
+                input.pos += 4;
+                if input.pos + len > data.len() || out.len() + len > MAX_OUTPUT { return None; }
+                out.extend_from_slice(&data[input.pos..input.pos + len]);
+                input.pos += len;
+            }
+            1 => {
+                let (lencode, distcode) = fixed_tables();
+                try_opt!(codes(&mut input, &mut out, &lencode, &distcode));
+            }
+            2 => {
+                let (lencode, distcode) = try_opt!(dynamic_tables(&mut input));
+                try_opt!(codes(&mut input, &mut out, &lencode, &distcode));
+            }
+            _ => return None,
+        }
+        if last { return Some(out); }
+    }
+}
+
+// CRC-32 as used by gzip and zip, for checking the unpacked data
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffff;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if (crc & 1) != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_blocks_check_nlen() {
+        assert_eq!(inflate(&[0x01, 0x03, 0x00, 0xfc, 0xff, b'a', b'b', b'c']), Some(b"abc".to_vec()));
+        assert_eq!(inflate(&[0x01, 0x03, 0x00, 0x00, 0x00, b'a', b'b', b'c']), None);
+    }
+
+    // A fixed Huffman block of one literal followed by `matches` copies of 258
+    // bytes at distance 1
+    fn run_of(matches: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        let (mut acc, mut count) = (0u32, 0);
+        {
+            // Huffman codes go out most significant bit first
+            let mut put = |value: u32, bits: u32, reversed: bool| {
+                for i in 0..bits {
+                    let bit = if reversed { (value >> (bits - 1 - i)) & 1 } else { (value >> i) & 1 };
+                    acc |= bit << count;
+                    count += 1;
+                    if count == 8 {
+                        out.push(acc as u8);
+                        acc = 0;
+                        count = 0;
+                    }
+                }
+            };
+            put(1, 1, false);           // BFINAL
+            put(1, 2, false);           // Fixed codes
+            put(0x30 + 0x61, 8, true);  // 'a'
+            for _ in 0..matches {
+                put(0xc5, 8, true);     // Length 258
+                put(0, 5, true);        // Distance 1
+            }
+            put(0, 7, true);            // End of block
+        }
+        if count > 0 { out.push(acc as u8); }
+        out
+    }
+
+    #[test]
+    fn output_is_capped_at_the_largest_rom() {
+        let rom = inflate(&run_of(32513)).unwrap();
+        assert_eq!(rom.len(), 1 + 258 * 32513);
+        assert!(rom.iter().all(|&b| b == b'a'));
+        assert_eq!(inflate(&run_of(32515)), None);
+    }
+}
@@ -0,0 +1,63 @@
+use sdl2::keyboard::Keycode;
+use sdl2::controller;
+
+// Joypad buttons, numbered after their bit in Memory::set_joypad
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Button {
+    Right   = 0,
+    Left    = 1,
+    Up      = 2,
+    Down    = 3,
+    A       = 4,
+    B       = 5,
+    Select  = 6,
+    Start   = 7,
+}
+
+const KEY_MAP: [(Keycode, Button); 8] = [
+    (Keycode::Right,     Button::Right),
+    (Keycode::Left,      Button::Left),
+    (Keycode::Up,        Button::Up),
+    (Keycode::Down,      Button::Down),
+    (Keycode::X,         Button::A),
+    (Keycode::Z,         Button::B),
+    (Keycode::Backspace, Button::Select),
+    (Keycode::Return,    Button::Start),
+];
+
+// Face buttons are mapped by position, the GameBoy A sits to the right of B
+const CONTROLLER_MAP: [(controller::Button, Button); 8] = [
+    (controller::Button::DPadRight, Button::Right),
+    (controller::Button::DPadLeft,  Button::Left),
+    (controller::Button::DPadUp,    Button::Up),
+    (controller::Button::DPadDown,  Button::Down),
+    (controller::Button::B,         Button::A),
+    (controller::Button::A,         Button::B),
+    (controller::Button::Back,      Button::Select),
+    (controller::Button::Start,     Button::Start),
+];
+
+pub fn key_button(key: Keycode) -> Option<Button> {
+    KEY_MAP.iter().find(|m| m.0 == key).map(|m| m.1)
+}
+
+pub fn controller_button(button: controller::Button) -> Option<Button> {
+    CONTROLLER_MAP.iter().find(|m| m.0 == button).map(|m| m.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn controller_buttons_map_by_position() {
+        assert_eq!(controller_button(controller::Button::DPadUp), Some(Button::Up));
+        assert_eq!(controller_button(controller::Button::B), Some(Button::A));
+        assert_eq!(controller_button(controller::Button::A), Some(Button::B));
+        assert_eq!(controller_button(controller::Button::Start), Some(Button::Start));
+        assert_eq!(controller_button(controller::Button::X), None);
+        for &(_, button) in CONTROLLER_MAP.iter() {
+            assert_eq!(CONTROLLER_MAP.iter().filter(|m| m.1 == button).count(), 1);
+        }
+    }
+}
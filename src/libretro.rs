@@ -0,0 +1,64 @@
+use cpu::CPU;
+
+// Mirrors the libretro core lifecycle so RustBoy can be wrapped by existing
+// frontends. https://docs.libretro.com/development/cores/developing-cores/
+pub trait RetroCore {
+    fn retro_init(&mut self);
+    fn retro_load_game(&mut self, data: &[u8]) -> bool;
+    // Runs a single frame
+    fn retro_run(&mut self);
+    // 160x144 shades, one byte per pixel
+    fn retro_get_framebuffer(&self) -> &[u8];
+    // Same bit layout as Memory::set_joypad
+    fn retro_set_input(&mut self, state: u8);
+    fn retro_serialize(&self) -> Option<Vec<u8>>;
+    fn retro_unserialize(&mut self, data: &[u8]) -> bool;
+}
+
+impl RetroCore for CPU {
+    fn retro_init(&mut self) {
+        self.reset();
+    }
+
+    fn retro_load_game(&mut self, data: &[u8]) -> bool {
+        self.load_rom_data(data.to_vec()).is_ok()
+    }
+
+    fn retro_run(&mut self) {
+        self.run_until_vblank();
+    }
+
+    fn retro_get_framebuffer(&self) -> &[u8] {
+        self.framebuffer()
+    }
+
+    fn retro_set_input(&mut self, state: u8) {
+        self.set_joypad(state);
+    }
+
+    fn retro_serialize(&self) -> Option<Vec<u8>> {
+        Some(self.save_state())
+    }
+
+    fn retro_unserialize(&mut self, data: &[u8]) -> bool {
+        self.load_state(data).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retro_run_produces_a_frame() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100] = 0x18; // JR -2
+        rom[0x101] = 0xfe;
+        let mut cpu = CPU::new_headless();
+        cpu.retro_init();
+        assert!(cpu.retro_load_game(&rom));
+        cpu.retro_run();
+        assert!(cpu.frame_ready());
+        assert_eq!(cpu.retro_get_framebuffer().len(), 160 * 144);
+    }
+}
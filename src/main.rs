@@ -1,35 +1,185 @@
 pub mod cartridge;
+pub mod inflate;
 pub mod memory;
 pub mod cpu;
 pub mod registers;
 pub mod gpu;
+pub mod timer;
+pub mod serial;
+pub mod overlay;
+pub mod libretro;
+pub mod movie;
+pub mod disasm;
+pub mod debugger;
+pub mod input;
+pub mod terminal;
+pub mod png;
+pub mod display;
+pub mod state;
 
 extern crate sdl2;
 
 use sdl2::pixels::Color;
+use sdl2::event::Event;
+use sdl2::keyboard::{Keycode, LALTMOD, RALTMOD};
+use sdl2::{GameControllerSubsystem, HapticSubsystem};
+use sdl2::controller::GameController;
+use sdl2::haptic::Haptic;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
 
-const MAX_CYCLES: u16 = 4194304;
+use std::env;
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MAX_CYCLES: u32 = 4194304;
+
+// A controller and its rumble motor, if it has one
+struct Gamepad {
+    controller: GameController,
+    haptic: Option<Haptic>,
+}
+
+fn open_gamepad(controllers: &GameControllerSubsystem, haptics: &HapticSubsystem, index: u32) -> Option<Gamepad> {
+    controllers.open(index).ok().map(|controller| Gamepad {
+        controller,
+        haptic: haptics.open_from_joystick_id(index).ok(),
+    })
+}
+
+// Opens the first connected controller, if any
+fn open_controller(controllers: &GameControllerSubsystem, haptics: &HapticSubsystem) -> Option<Gamepad> {
+    let count = controllers.num_joysticks().unwrap_or(0);
+    (0..count).filter(|i| controllers.is_game_controller(*i))
+              .filter_map(|i| open_gamepad(controllers, haptics, i))
+              .next()
+}
+
+// The output device lives as long as the main loop. It's paused along with
+// the emulation and closed before exit, so no stale buffer keeps sounding
+fn open_audio(sdl: &sdl2::Sdl) -> Option<AudioQueue<i16>> {
+    let desired = AudioSpecDesired { freq: Some(44100), channels: Some(2), samples: Some(1024) };
+    sdl.audio().ok().and_then(|audio| audio.open_queue::<i16, _>(None, &desired).ok())
+}
 
 fn main() {
 	let sdl_context = sdl2::init().unwrap();
     let video = sdl_context.video().unwrap();
 
     let window = video.window("rustyboy", 160, 144)
-        .position_centered().opengl()
+        .position_centered().opengl().resizable()
         .build().unwrap();
 
-    let mut renderer = window.renderer()
+    let mut canvas = window.into_canvas()
         .accelerated()
         .build().unwrap();
 
-    renderer.set_draw_color(Color::RGB(0, 0, 0));
-    renderer.clear();
+    canvas.set_draw_color(Color::RGB(0, 0, 0));
+    canvas.clear();
 
-    let mut cpu = cpu::CPU::new(renderer);
+    let mut events = sdl_context.event_pump().unwrap();
+    let controllers = sdl_context.game_controller().unwrap();
+    let haptics = sdl_context.haptic().unwrap();
+    let mut gamepad = open_controller(&controllers, &haptics);
+    let audio = open_audio(&sdl_context);
+
+    let mut cpu = cpu::CPU::new(Box::new(display::SdlDisplay::new(canvas)));
     cpu.initialize("t.gb");
-    let mut b = true;
-    while b {
-        cpu.cpu_cycle();
+
+    if env::args().any(|arg| arg == "--debug") {
+        let stdin = io::stdin();
+        if let Err(err) = debugger::Debugger::new().run(&mut cpu, stdin.lock(), io::stdout()) {
+            println!("Error: {}", err);
+        }
+        return;
+    }
+
+    let frame_time = Duration::new(0, (gpu::FRAME_CYCLES as u64 * 1_000_000_000 / MAX_CYCLES as u64) as u32);
+
+    if let Some(ref device) = audio { device.resume(); }
+
+    let mut paused = false;
+    let mut show_overlay = false;
+    let mut rumbling = false;
+    let mut fps = 0;
+    let mut frames = 0;
+    let mut fps_timer = Instant::now();
+
+    'running: loop {
+        let start = Instant::now();
+
+        if !paused {
+            cpu.run_until_vblank();
+        }
+
+        // Refreshed every frame while the motor runs, stopped once it's released
+        let rumble = cpu.rumble_state();
+        if rumble || rumble != rumbling {
+            if let Some(h) = gamepad.as_mut().and_then(|g| g.haptic.as_mut()) {
+                if rumble { h.rumble_play(1.0, 100); } else { h.rumble_stop(); }
+            }
+        }
+        rumbling = rumble;
+
+        for event in events.poll_iter() {
+            match event {
+                Event::Quit { .. } => break 'running,
+                Event::KeyDown { keycode: Some(Keycode::P), repeat: false, .. } => {
+                    paused = !paused;
+                    if let Some(ref device) = audio {
+                        // Whatever was queued before the pause is dropped, not replayed
+                        if paused { device.pause(); device.clear(); } else { device.resume(); }
+                    }
+                }
+                Event::KeyDown { keycode: Some(Keycode::F1), repeat: false, .. } => show_overlay = !show_overlay,
+                Event::KeyDown { keycode: Some(Keycode::F2), repeat: false, .. } => cpu.toggle_frame_blend(),
+                Event::KeyDown { keycode: Some(Keycode::F11), repeat: false, .. } => cpu.toggle_fullscreen(),
+                Event::KeyDown { keycode: Some(Keycode::Return), keymod, repeat: false, .. }
+                    if keymod.intersects(LALTMOD | RALTMOD) => cpu.toggle_fullscreen(),
+                Event::KeyDown { keycode: Some(key), repeat: false, .. } => {
+                    if let Some(button) = input::key_button(key) { cpu.set_button(button, true); }
+                }
+                Event::KeyUp { keycode: Some(key), .. } => {
+                    if let Some(button) = input::key_button(key) { cpu.set_button(button, false); }
+                }
+                Event::ControllerDeviceAdded { which, .. } if gamepad.is_none() => {
+                    gamepad = open_gamepad(&controllers, &haptics, which);
+                }
+                Event::ControllerDeviceRemoved { which, .. }
+                    if gamepad.as_ref().is_some_and(|g| g.controller.instance_id() == which) => {
+                    gamepad = open_controller(&controllers, &haptics);
+                }
+                Event::ControllerButtonDown { button, .. } => {
+                    if let Some(button) = input::controller_button(button) { cpu.set_button(button, true); }
+                }
+                Event::ControllerButtonUp { button, .. } => {
+                    if let Some(button) = input::controller_button(button) { cpu.set_button(button, false); }
+                }
+                _ => {}
+            }
+        }
+
+        if show_overlay {
+            cpu.present_frame_with_overlay(fps);
+        } else {
+            cpu.present_frame();
+        }
+
+        frames += 1;
+        if fps_timer.elapsed() >= Duration::from_secs(1) {
+            fps = frames;
+            frames = 0;
+            fps_timer = Instant::now();
+        }
+
+        let elapsed = start.elapsed();
+        if elapsed < frame_time {
+            thread::sleep(frame_time - elapsed);
+        }
+    }
+
+    if let Some(device) = audio {
+        device.pause();
+        drop(device);
     }
-    
 }
\ No newline at end of file
@@ -1,62 +1,364 @@
-﻿use gpu::GPU;
+﻿use gpu::{self, GPU, FrameSink};
+use cartridge::{Cartridge, RamInit};
+use timer::{self, Timer};
+use serial::{self, Serial};
+use input::Button;
+use state::{StateReader, StateWriter};
+use cpu::IFlags;
 use cpu::IFlags::{ VBLANK, LCDCSTATUS, TIMEROVERFLOW, SERIALTC, KEYPAD };
 
-use std::io;
-use sdl2::render::Renderer;
-use sdl2::pixels::Color;
-use sdl2::rect::Point;
+use std::collections::VecDeque;
+
+const INPUT_LOG_SIZE: usize = 256;
+
+// Joypad state change as seen by the game
+#[derive(Copy, Clone, Debug)]
+pub struct InputEvent {
+    pub frame: u64,
+    pub buttons: u8,
+    pub p1: u8,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Model {
+    DMG,
+    CGB,
+}
 
 // https://realboyemulator.files.wordpress.com/2013/01/gbcpuman.pdf Page 8
 pub struct Memory {
-    cart: [u8; 0x8000], // Cartridge   
-    sram: [u8; 0x2000], // Switchable RAM bank
+    cart: Cartridge, // Cartridge ROM and switchable RAM banks
     iram: [u8; 0x2000], // Internal RAM
     eram: [u8; 0x2000], // Echo of Internal RAM
     io:   [u8; 0x100], // IO
     hram: [u8; 0x80], // Internal RAM 
+    joypad: u8,        // Pressed buttons, see Memory::set_joypad
+    joypad_select: u8, // P1 bits 4-5
+    dma: u8,           // Last OAM DMA source high byte
+    dma_ticks: u32,    // Cycles of the last OAM DMA not yet accounted for by the CPU
+    dma_left: u8,      // Bytes of a cycle-stepped OAM DMA still to copy
+    pub cycle_dma: bool, // OAM DMA copies a byte per machine cycle, see dma_cycle
+    double_speed: bool, // KEY1 bit 7, CGB only
+    speed_prepare: bool, // KEY1 bit 0, CGB only
+    wave_playing: bool, // Channel 3 triggered with its DAC on
+    hdma_source: u16,   // HDMA1-2, CGB only
+    hdma_dest: u16,     // HDMA3-4, offset into VRAM
+    hdma_blocks: u8,    // HDMA5 bits 0-6, 16 byte blocks left minus one
+    hdma_active: bool,  // An HBlank transfer is running
+    pub model: Model,
+    frame: u64,
+    input_log: VecDeque<InputEvent>,
+    pub input_logging: bool,
     pub master: bool,
     pub enable: u8,
     pub flags: u8,
     pub gpu: GPU,
+    pub timer: Timer,
+    pub serial: Serial,
 }
 
 impl Memory {
-    pub fn new(rend: Renderer<'static>) -> Memory {
+    pub fn new(sink: Box<dyn FrameSink>) -> Memory {
+        Memory::with_gpu(GPU::new(sink))
+    }
+
+    pub fn headless() -> Memory {
+        Memory::with_gpu(GPU::headless())
+    }
+
+    fn with_gpu(gpu: GPU) -> Memory {
          Memory {
-            cart:   [0; 0x8000], 
-            sram:   [0; 0x2000],
+            cart:   Cartridge::empty(),
             iram:   [0; 0x2000],
             eram:   [0; 0x2000],
             io:     [0; 0x100],   // https://realboyemulator.files.wordpress.com/2013/01/gbcpuman.pdf Page 35 Special IO Registers
             hram:   [0; 0x80],           
+            joypad: 0,
+            joypad_select: 0x30,
+            dma: 0,
+            dma_ticks: 0,
+            dma_left: 0,
+            cycle_dma: false,
+            double_speed: false,
+            speed_prepare: false,
+            wave_playing: false,
+            hdma_source: 0,
+            hdma_dest: 0,
+            hdma_blocks: 0x7f,
+            hdma_active: false,
+            model: Model::DMG,
+            frame: 0,
+            input_log: VecDeque::new(),
+            input_logging: false,
             master: false,
             enable: 0,
             flags: 0,
-            gpu: GPU::new(rend),           
+            gpu,           
+            timer: Timer::new(),
+            serial: Serial::new(),
         }      
     }
 
-    pub fn gpu_cycle(&mut self, cputicks: u32) {
-        if self.gpu.gpu_cycle(cputicks, self.flags, self.enable) {
-            self.flags |= VBLANK as u8;
+    pub fn load_cartridge(&mut self, cart: Cartridge) {
+        self.cart = cart;
+    }
+
+    pub fn init_cart_ram(&mut self, init: RamInit) {
+        self.cart.init_ram(init);
+    }
+
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.cart
+    }
+
+    pub fn rumble_state(&self) -> bool {
+        self.cart.rumble_state()
+    }
+
+    pub fn map_summary(&self) -> String {
+        self.cart.map_summary()
+    }
+
+    // Fills the internal RAM with a reproducible pattern standing in for the
+    // random power-on contents. A seed of 0 leaves it cleared.
+    pub fn seed_ram(&mut self, seed: u32) {
+        let mut x = seed;
+        for i in 0..0x2000 {
+            if seed != 0 {
+                // xorshift32
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+            }
+            self.iram[i] = x as u8;
+        }
+        for i in 0..0x80 {
+            self.hram[i] = self.iram[i];
+        }
+    }
+
+    // Bits 0-3: Right, Left, Up, Down. Bits 4-7: A, B, Select, Start. Set = pressed
+    // A selected line going from high to low requests the joypad interrupt
+    pub fn set_joypad(&mut self, state: u8) {
+        let changed = self.joypad != state;
+        let lines = self.read_joypad();
+        self.joypad = state;
+        if (lines & !self.read_joypad() & 0x0f) != 0 {
+            self.request_interrupt(KEYPAD);
+        }
+        if changed && self.input_logging {
+            if self.input_log.len() == INPUT_LOG_SIZE {
+                self.input_log.pop_front();
+            }
+            let event = InputEvent { frame: self.frame, buttons: state, p1: self.read_joypad() };
+            self.input_log.push_back(event);
+        }
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        let mask = 1 << (button as u8);
+        let state = if pressed { self.joypad | mask } else { self.joypad & !mask };
+        self.set_joypad(state);
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.set_button(button, true);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.set_button(button, false);
+    }
+
+    // Last joypad changes, oldest first. Only recorded while input_logging is set
+    pub fn input_log(&self) -> &VecDeque<InputEvent> {
+        &self.input_log
+    }
+
+    // http://gbdev.gg8.se/wiki/articles/Joypad_Input
+    // The lines are low active: writing 0x20 selects the directions and 0x10 the
+    // actions. The nibble is built from the select bits on every read, so a read
+    // right after a write always sees the newly selected group
+    fn read_joypad(&self) -> u8 {
+        let mut v = 0x0f;
+        if (self.joypad_select & 0x10) == 0 { v &= !(self.joypad & 0x0f); }
+        if (self.joypad_select & 0x20) == 0 { v &= !(self.joypad >> 4); }
+        0xc0 | self.joypad_select | v
+    }
+
+    // True while a button of a selected group is held, this is what ends STOP
+    pub fn joypad_line_low(&self) -> bool {
+        (self.read_joypad() & 0x0f) != 0x0f
+    }
+
+    // KEY1 only exists on the CGB, the DMG reads an open bus
+    fn read_key1(&self) -> u8 {
+        match self.model {
+            Model::DMG => 0xff,
+            Model::CGB => 0x7e | (if self.double_speed { 0x80 } else { 0 })
+                               | (if self.speed_prepare { 0x01 } else { 0 }),
+        }
+    }
+
+    // Called by STOP, switches the CGB speed if it was prepared through KEY1
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.speed_prepare { return false; }
+        self.speed_prepare = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+
+    pub fn request_interrupt(&mut self, flag: IFlags) {
+        self.flags |= flag as u8;
+    }
+
+    pub fn clear_interrupt(&mut self, flag: IFlags) {
+        self.flags &= !(flag as u8);
+    }
+
+    pub fn is_interrupt_pending(&self, flag: IFlags) -> bool {
+        (self.flags & flag as u8) != 0
+    }
+
+    pub fn timer_cycle(&mut self, cycles: u32) {
+        if self.timer.step(cycles) {
+            self.request_interrupt(TIMEROVERFLOW);
+        }
+    }
+
+    pub fn serial_cycle(&mut self, cycles: u32) {
+        if self.serial.step(cycles) {
+            self.request_interrupt(SERIALTC);
         }
     }
 
+    pub fn gpu_cycle(&mut self, cycles: u32) -> bool {
+        let mode = self.gpu.mode();
+        let vblank = self.gpu.gpu_cycle(cycles);
+        if self.gpu.take_stat_irq() {
+            self.request_interrupt(LCDCSTATUS);
+        }
+        // HBlank DMA moves one block each time the PPU enters HBlank
+        if self.hdma_active && mode != 0 && self.gpu.mode() == 0 {
+            self.hdma_block();
+        }
+        if vblank {
+            self.request_interrupt(VBLANK);
+            self.frame += 1;
+        }
+        vblank
+    }
+
+    // Puts everything but the cartridge ROM and RAM back to its power on state,
+    // so a reset machine behaves exactly like a freshly created one
+    pub fn reset(&mut self) {
+        self.iram = [0; 0x2000];
+        self.eram = [0; 0x2000];
+        self.io = [0; 0x100];
+        self.hram = [0; 0x80];
+        self.joypad = 0;
+        self.joypad_select = 0x30;
+        self.double_speed = false;
+        self.speed_prepare = false;
+        self.wave_playing = false;
+        self.hdma_source = 0;
+        self.hdma_dest = 0;
+        self.hdma_blocks = 0x7f;
+        self.hdma_active = false;
+        self.frame = 0;
+        self.dma_ticks = 0;
+        self.dma_left = 0;
+        self.input_log.clear();
+        self.master = false;
+        self.enable = 0;
+        self.cart.reset_banks();
+        self.gpu.reset();
+        self.timer = Timer::new();
+        self.serial.reset();
+        self.put_initial();
+    }
+
+    // The pressed buttons belong to the frontend and aren't saved
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.iram);
+        w.bytes(&self.eram);
+        w.bytes(&self.io);
+        w.bytes(&self.hram);
+        w.u8(self.joypad_select);
+        w.u8(self.dma);
+        w.u32(self.dma_ticks);
+        w.u8(self.dma_left);
+        w.bool(self.double_speed);
+        w.bool(self.speed_prepare);
+        w.bool(self.wave_playing);
+        w.u16(self.hdma_source);
+        w.u16(self.hdma_dest);
+        w.u8(self.hdma_blocks);
+        w.bool(self.hdma_active);
+        w.u64(self.frame);
+        w.bool(self.master);
+        w.u8(self.enable);
+        w.u8(self.flags);
+        self.cart.save_state(w);
+        self.gpu.save_state(w);
+        self.timer.save_state(w);
+        self.serial.save_state(w);
+    }
+
+    // Bytes written by save_state, only the cartridge RAM varies
+    pub fn state_size(&self) -> usize {
+        0x2000 + 0x2000 + 0x100 + 0x80 + 27 + self.cart.state_size() +
+            gpu::STATE_SIZE + timer::STATE_SIZE + serial::STATE_SIZE
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) {
+        r.bytes(&mut self.iram);
+        r.bytes(&mut self.eram);
+        r.bytes(&mut self.io);
+        r.bytes(&mut self.hram);
+        self.joypad_select = r.u8();
+        self.dma = r.u8();
+        self.dma_ticks = r.u32();
+        self.dma_left = r.u8();
+        self.double_speed = r.bool();
+        self.speed_prepare = r.bool();
+        self.wave_playing = r.bool();
+        self.hdma_source = r.u16();
+        self.hdma_dest = r.u16();
+        self.hdma_blocks = r.u8();
+        self.hdma_active = r.bool();
+        self.frame = r.u64();
+        self.master = r.bool();
+        self.enable = r.u8();
+        self.flags = r.u8();
+        self.cart.load_state(r);
+        self.gpu.load_state(r);
+        self.timer.load_state(r);
+        self.serial.load_state(r);
+    }
+
     // https://realboyemulator.files.wordpress.com/2013/01/gbcpuman.pdf Page 18
+    // http://gbdev.gg8.se/wiki/articles/Power_Up_Sequence
     pub fn put_initial(&mut self) { 
+        self.write_byte(0xff00, 0xcf);
+        self.write_byte(0xff01, 0);
+        self.write_byte(0xff02, 0x7e);
         self.write_byte(0xff05, 0);
         self.write_byte(0xff06, 0);
-        self.write_byte(0xff07, 0);
+        self.write_byte(0xff07, 0xf8);
+        self.write_byte(0xff0f, 0xe1);
         self.write_byte(0xff10, 0x80);
         self.write_byte(0xff11, 0xbf);
         self.write_byte(0xff12, 0xf3);
+        self.write_byte(0xff13, 0xff);
         self.write_byte(0xff14, 0xbf);
         self.write_byte(0xff16, 0x3f);
         self.write_byte(0xff17, 0);
+        self.write_byte(0xff18, 0xff);
         self.write_byte(0xff19, 0xbf);
         self.write_byte(0xff1a, 0x7f);
         self.write_byte(0xff1b, 0xff);
         self.write_byte(0xff1c, 0x9f);
+        self.write_byte(0xff1d, 0xff);
         self.write_byte(0xff1e, 0xbf);
         self.write_byte(0xff20, 0xff);
         self.write_byte(0xff21, 0);
@@ -66,9 +368,11 @@ impl Memory {
         self.write_byte(0xff25, 0xf3);
         self.write_byte(0xff26, 0xf1);
         self.write_byte(0xff40, 0x91);
+        self.write_byte(0xff41, 0x85);
         self.write_byte(0xff42, 0);
         self.write_byte(0xff43, 0);
         self.write_byte(0xff45, 0);
+        self.dma = 0xff; // Writing 0xff46 would start a DMA
         self.write_byte(0xff47, 0xfc);
         self.write_byte(0xff48, 0xff);
         self.write_byte(0xff49, 0xff);
@@ -77,82 +381,193 @@ impl Memory {
         self.write_byte(0xffff, 0);      
     }
     
+    #[allow(ellipsis_inclusive_range_patterns, clippy::match_overlapping_arm)]
     pub fn read_byte(&mut self, address: u16) -> u8 {
         match address {
-            0x0000 ... 0x7fff => { self.cart[address as usize] }
+            0x0000 ... 0x7fff => { self.cart.read_rom(address) }
             0x8000 ... 0x9fff => { self.gpu.vram[address as usize - 0x8000] }
-            0xa000 ... 0xbfff => { self.sram[address as usize - 0xa000] }
+            0xa000 ... 0xbfff => { self.cart.read_ram(address) }
             0xc000 ... 0xdfff => { self.iram[address as usize - 0xc000] }
             0xe000 ... 0xfdff => { self.eram[address as usize - 0xe000] }
+            0xfe00 ... 0xfeff if self.dma_left != 0 => { 0xff }
             0xfe00 ... 0xfeff => { self.gpu.oam[address as usize - 0xfe00] }
-            0xff00 => { 0 }
-            0xff04 => { 1 }
+            0xff00 => { self.read_joypad() }
+            0xff01 ... 0xff02 => { self.serial.read_byte(address) }
+            0xff04 ... 0xff07 => { self.timer.read_byte(address) }
             //0xff40 => { self.gpu.lcd_control }
             0xff40 => { (if self.gpu.switchbg { 0x01 } else { 0x0 }) |
+                        (if self.gpu.switchobj { 0x02 } else { 0x0 }) |
+                        (if self.gpu.sprite_size { 0x04 } else { 0x0 }) |
                         (if self.gpu.bg_map   { 0x08 } else { 0x0 }) |
                         (if self.gpu.bg_tile  { 0x10 } else { 0x0 }) |
+                        (if self.gpu.switchwin { 0x20 } else { 0x0 }) |
+                        (if self.gpu.window_map { 0x40 } else { 0x0 }) |
                         (if self.gpu.lcd_on   { 0x80 } else { 0x0 })
                       }
+            0xff41 => { self.gpu.read_stat() }
             0xff42 => { self.gpu.scroll_y }
             0xff43 => { self.gpu.scroll_x }
-            0xff44 => { self.gpu.scanline }
+            0xff44 => { self.gpu.ly() }
+            0xff45 => { self.gpu.lyc }
+            0xff46 => { self.dma }
+            0xff47 => { self.gpu.bgp }
+            0xff48 => { self.gpu.obp0 }
+            0xff49 => { self.gpu.obp1 }
             0xff4a => { self.gpu.win_y }
             0xff4b => { self.gpu.win_x }
+            0xff4d => { self.read_key1() }
+            0xff55 if self.model == Model::CGB => { (if self.hdma_active { 0x00 } else { 0x80 }) | self.hdma_blocks }
             0xff0f => { self.flags }
+            // On DMG the CPU can't see wave RAM while channel 3 is reading it
+            0xff30 ... 0xff3f if self.wave_playing && self.model == Model::DMG => { 0xff }
             0xff00 ... 0xff7f => { self.io[address as usize - 0xff00] }
             0xff80 ... 0xfffe => { self.hram[address as usize - 0xff80] }   
             0xffff => { self.enable }   
-            _ => 1
         }
     }
 
+    #[allow(ellipsis_inclusive_range_patterns, clippy::match_overlapping_arm)]
     pub fn write_byte(&mut self, address: u16, value: u8) {
         match address {
-            0x0000 ... 0x7fff => { self.cart[address as usize] = value; }
+            0x0000 ... 0x7fff => { self.cart.write_rom(address, value); }
             0x8000 ... 0x9fff => { self.gpu.vram[address as usize - 0x8000] = value;
-                                   if address < 0x97ff { self.gpu.update_tile(address, value); }
+                                   self.gpu.invalidate_tile(address);
                                  }
-            0xa000 ... 0xbfff => { self.sram[address as usize - 0xa000] = value; }
+            0xa000 ... 0xbfff => { self.cart.write_ram(address, value); }
             0xc000 ... 0xdfff => { self.iram[address as usize - 0xc000] = value; }
             0xe000 ... 0xfdff => { self.eram[address as usize - 0xe000] = value; }
+            0xfe00 ... 0xfeff if self.dma_left != 0 => { }
             0xfe00 ... 0xfeff => { self.gpu.oam[address as usize - 0xfe00] = value; }
+            0xff00 => { self.joypad_select = value & 0x30; }
+            0xff01 ... 0xff02 => { self.serial.write_byte(address, value); }
+            0xff04 ... 0xff07 => { self.timer.write_byte(address, value); }
             //0xff40 => { self.gpu.lcd_control = value; }
-            0xff40 => { self.gpu.switchbg = (if (value & 0x01) != 0 { true } else { false });
-                        self.gpu.bg_map   = (if (value & 0x08) != 0 { true } else { false });
-                        self.gpu.bg_tile  = (if (value & 0x10) != 0 { true } else { false });
-                        self.gpu.lcd_on   = (if (value & 0x80) != 0 { true } else { false });
+            0xff40 => { self.gpu.switchbg = (value & 0x01) != 0;
+                        self.gpu.switchobj = (value & 0x02) != 0;
+                        self.gpu.sprite_size = (value & 0x04) != 0;
+                        self.gpu.bg_map   = (value & 0x08) != 0;
+                        self.gpu.bg_tile  = (value & 0x10) != 0;
+                        self.gpu.switchwin = (value & 0x20) != 0;
+                        self.gpu.window_map = (value & 0x40) != 0;
+                        self.gpu.set_lcd_on((value & 0x80) != 0);
+                      }
+            0xff41 => { self.gpu.set_stat(value);
+                        if self.gpu.take_stat_irq() { self.request_interrupt(LCDCSTATUS); }
                       }
             0xff42 => { self.gpu.scroll_y = value; }
             0xff43 => { self.gpu.scroll_x = value; }
-            0xff46 => { self.oam_to_ram(value); }
+            0xff45 => { self.gpu.set_lyc(value);
+                        if self.gpu.take_stat_irq() { self.request_interrupt(LCDCSTATUS); }
+                      }
+            0xff46 => { self.dma = value;
+                        self.oam_to_ram(value);
+                      }
             0xff47 => { self.gpu.u_palette_b(value); }
             0xff48 => { self.gpu.u_s_palette0(value); }
             0xff49 => { self.gpu.u_s_palette1(value); }
             0xff4a => { self.gpu.win_y = value; }
             0xff4b => { self.gpu.win_x = value; }
+            0xff4d => { if self.model == Model::CGB { self.speed_prepare = (value & 0x01) != 0; } }
+            0xff51 ... 0xff55 if self.model == Model::CGB => { self.write_hdma(address, value); }
             0xff0f => { self.flags = value; }
+            0xff1a => { self.io[0x1a] = value;
+                        if (value & 0x80) == 0 { self.wave_playing = false; }
+                      }
+            0xff1e => { self.io[0x1e] = value;
+                        if (value & 0x80) != 0 && (self.io[0x1a] & 0x80) != 0 { self.wave_playing = true; }
+                      }
+            0xff26 => { self.io[0x26] = value;
+                        if (value & 0x80) == 0 { self.wave_playing = false; }
+                      }
             0xff00 ... 0xff7f => { self.io[address as usize - 0xff00] = value }
             0xff80 ... 0xfffe => { self.hram[address as usize - 0xff80] = value }
-            //0xffff => { self.enable = value; }
-            _ => {  }
+            0xffff => { self.enable = value; }
+        }
+    }
+
+    // http://gbdev.gg8.se/wiki/articles/Video_Display#LCD_VRAM_DMA_Transfers_.28CGB_only.29
+    // A general transfer completes at once, the CPU isn't held for its duration
+    fn write_hdma(&mut self, address: u16, value: u8) {
+        match address {
+            0xff51 => { self.hdma_source = (self.hdma_source & 0x00ff) | ((value as u16) << 8); }
+            0xff52 => { self.hdma_source = (self.hdma_source & 0xff00) | (value & 0xf0) as u16; }
+            0xff53 => { self.hdma_dest = (self.hdma_dest & 0x00ff) | (((value & 0x1f) as u16) << 8); }
+            0xff54 => { self.hdma_dest = (self.hdma_dest & 0xff00) | (value & 0xf0) as u16; }
+            _ => {
+                self.hdma_blocks = value & 0x7f;
+                if self.hdma_active && (value & 0x80) == 0 {
+                    // Clearing bit 7 stops a running HBlank transfer
+                    self.hdma_active = false;
+                } else if (value & 0x80) != 0 {
+                    self.hdma_active = true;
+                } else {
+                    while self.hdma_block() {}
+                }
+            }
+        }
+    }
+
+    // Copies one 16 byte block, returns false once the transfer is complete.
+    // Goes through write_byte so the tile cache follows
+    fn hdma_block(&mut self) -> bool {
+        for i in 0..16 {
+            let b = self.read_byte(self.hdma_source.wrapping_add(i));
+            self.write_byte(0x8000 | ((self.hdma_dest + i) & 0x1fff), b);
         }
+        self.hdma_source = self.hdma_source.wrapping_add(16);
+        self.hdma_dest = (self.hdma_dest + 16) & 0x1ff0;
+        if self.hdma_blocks == 0 {
+            self.hdma_blocks = 0x7f;
+            self.hdma_active = false;
+            return false;
+        }
+        self.hdma_blocks -= 1;
+        true
     }
 
+    // The copy happens at once, its 160 machine cycles are charged to the CPU
+    // through take_dma_ticks. With cycle_dma it's left to dma_cycle instead.
+    // Sources above 0xdf read the work RAM they echo
     fn oam_to_ram(&mut self, value: u8) {
-        let v = (value as u16) << 8;
+        if self.cycle_dma {
+            self.dma_left = 0xa0;
+            return;
+        }
+        let page = if value >= 0xe0 { value - 0x20 } else { value };
+        let v = (page as u16) << 8;
         for i in 0 .. 0xa0 {
             let b = self.read_byte(v + i);
             self.write_byte(0xfe00 + i, b);
         }
+        self.dma_ticks = 640;
+    }
+
+    // Copies a byte per machine cycle of a running OAM DMA. OAM reads 0xff and
+    // ignores writes until the last byte is in
+    pub fn dma_cycle(&mut self, cycles: u32) {
+        for _ in 0 .. cycles / 4 {
+            if self.dma_left == 0 { return; }
+            let page = if self.dma >= 0xe0 { self.dma - 0x20 } else { self.dma };
+            let i = 0xa0 - self.dma_left as u16;
+            let b = self.read_byte(((page as u16) << 8) + i);
+            self.gpu.oam[i as usize] = b;
+            self.dma_left -= 1;
+        }
+    }
+
+    pub fn take_dma_ticks(&mut self) -> u32 {
+        let ticks = self.dma_ticks;
+        self.dma_ticks = 0;
+        ticks
     }
 
     pub fn read_short(&mut self, address: u16) -> u16 {
-        (self.read_byte(address) as u16 | ((self.read_byte(address + 1) as u16) << 8))
+        self.read_byte(address) as u16 | ((self.read_byte(address.wrapping_add(1)) as u16) << 8)
     }
 
     pub fn write_short(&mut self, address: u16, value: u16) {
         self.write_byte(address, (value & 0xff) as u8);   
-        self.write_byte(address + 1, (value >> 8) as u8);
+        self.write_byte(address.wrapping_add(1), (value >> 8) as u8);
     }
 
     pub fn debug_memory(&mut self) {
@@ -160,4 +575,265 @@ impl Memory {
         println!("{:X} IE", self.enable);
         println!("{:X} IF", self.flags);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cartridge::{self, MBC};
+    use gpu::TileMap;
+
+    #[test]
+    fn dma_register_reads_back_the_source() {
+        let mut mem = Memory::headless();
+        mem.write_byte(0xff46, 0xc1);
+        assert_eq!(mem.read_byte(0xff46), 0xc1);
+    }
+
+    #[test]
+    fn put_initial_sets_the_post_boot_registers() {
+        let mut mem = Memory::headless();
+        mem.put_initial();
+        let expected = [(0xff05, 0x00), (0xff06, 0x00), (0xff07, 0xf8), (0xff0f, 0xe1),
+                        (0xff40, 0x91), (0xff42, 0x00), (0xff43, 0x00),
+                        (0xff45, 0x00), (0xff46, 0xff), (0xff47, 0xfc),
+                        (0xff48, 0xff), (0xff49, 0xff), (0xff4a, 0x00),
+                        (0xff4b, 0x00), (0xffff, 0x00)];
+        for &(address, value) in expected.iter() {
+            assert_eq!(mem.read_byte(address), value, "register {:04X}", address);
+        }
+    }
+
+    #[test]
+    fn key1_reads_the_speed_switch_state() {
+        let mut mem = Memory::headless();
+        mem.write_byte(0xff4d, 0x01);
+        assert_eq!(mem.read_byte(0xff4d), 0xff);
+
+        mem.model = Model::CGB;
+        assert_eq!(mem.read_byte(0xff4d), 0x7e);
+        mem.write_byte(0xff4d, 0x01);
+        assert_eq!(mem.read_byte(0xff4d), 0x7f);
+        assert!(mem.switch_speed());
+        assert_eq!(mem.read_byte(0xff4d), 0xfe);
+    }
+
+    #[test]
+    fn palette_registers_read_back_the_raw_byte() {
+        let mut mem = Memory::headless();
+        mem.write_byte(0xff47, 0xe4);
+        mem.write_byte(0xff48, 0x1b);
+        mem.write_byte(0xff49, 0xd2);
+        assert_eq!(mem.read_byte(0xff47), 0xe4);
+        assert_eq!(mem.read_byte(0xff48), 0x1b);
+        assert_eq!(mem.read_byte(0xff49), 0xd2);
+    }
+
+    #[test]
+    fn input_log_records_each_change() {
+        let mut mem = Memory::headless();
+        mem.input_logging = true;
+        mem.write_byte(0xff00, 0x10); // Select the action buttons
+        mem.press(Button::A);
+        mem.press(Button::A);
+        mem.release(Button::A);
+        let log: Vec<InputEvent> = mem.input_log().iter().cloned().collect();
+        assert_eq!(log.len(), 2);
+        assert_eq!((log[0].buttons, log[0].p1), (0x10, 0xde));
+        assert_eq!((log[1].buttons, log[1].p1), (0x00, 0xdf));
+    }
+
+    #[test]
+    fn wave_ram_is_hidden_while_channel_3_plays() {
+        let mut mem = Memory::headless();
+        mem.write_byte(0xff30, 0x12);
+        mem.write_byte(0xff1a, 0x80);
+        mem.write_byte(0xff1e, 0x80);
+        assert!(mem.read_byte(0xff30) != 0x12);
+
+        mem.write_byte(0xff1a, 0x00);
+        assert_eq!(mem.read_byte(0xff30), 0x12);
+    }
+
+    #[test]
+    fn request_interrupt_sets_its_if_bit() {
+        let mut mem = Memory::headless();
+        mem.request_interrupt(VBLANK);
+        assert_eq!(mem.read_byte(0xff0f), 0x01);
+        assert!(mem.is_interrupt_pending(VBLANK));
+        assert!(!mem.is_interrupt_pending(TIMEROVERFLOW));
+        mem.request_interrupt(TIMEROVERFLOW);
+        mem.clear_interrupt(VBLANK);
+        assert_eq!(mem.read_byte(0xff0f), 0x04);
+        assert!(!mem.is_interrupt_pending(VBLANK));
+    }
+
+    #[test]
+    fn rumble_follows_the_ram_bank_bit() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x104] = 0xce;
+        rom[0x147] = 0x1e; // MBC5 + rumble + SRAM + battery
+        let mut mem = Memory::headless();
+        mem.load_cartridge(Cartridge::new(rom, MBC::MBC5, 0x2000));
+        assert!(!mem.rumble_state());
+        mem.write_byte(0x4000, 0x08);
+        assert!(mem.rumble_state());
+        mem.write_byte(0x4000, 0x01);
+        assert!(!mem.rumble_state());
+    }
+
+    #[test]
+    fn p1_reads_the_group_just_selected() {
+        let mut mem = Memory::headless();
+        mem.press(Button::A);
+        mem.press(Button::Down);
+        // A clear bit 5 selects the action buttons, a clear bit 4 the directions
+        mem.write_byte(0xff00, 0x10);
+        assert_eq!(mem.read_byte(0xff00), 0xde);
+        mem.write_byte(0xff00, 0x20);
+        assert_eq!(mem.read_byte(0xff00), 0xe7);
+        mem.write_byte(0xff00, 0x10);
+        assert_eq!(mem.read_byte(0xff00), 0xde);
+    }
+
+    #[test]
+    fn ie_round_trips_through_0xffff() {
+        let mut mem = Memory::headless();
+        mem.write_byte(0xffff, 0x1f);
+        assert_eq!(mem.read_byte(0xffff), 0x1f);
+        assert_eq!(mem.enable, 0x1f);
+    }
+
+    #[test]
+    fn tima_at_4096_hz_raises_the_timer_interrupt() {
+        let mut mem = Memory::headless();
+        mem.write_byte(0xff05, 0);
+        mem.write_byte(0xff07, 0x04);
+        for _ in 0..256 * 1024 / 4 {
+            mem.timer_cycle(4);
+        }
+        // The overflow shows a cycle later, once TMA is reloaded
+        assert!(!mem.is_interrupt_pending(TIMEROVERFLOW));
+        mem.timer_cycle(4);
+        assert!(mem.is_interrupt_pending(TIMEROVERFLOW));
+    }
+
+    // Memory with a CGB cartridge loaded, set up to copy from 0xc000 to 0x8000
+    fn hdma_memory() -> Memory {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        let mut mem = Memory::headless();
+        cartridge::load_rom_data(rom, &mut mem).unwrap();
+        mem.write_byte(0xff40, 0x91);
+        for i in 0..0x40 {
+            mem.write_byte(0xc000 + i, 0x40 + i as u8);
+        }
+        mem.write_byte(0xff51, 0xc0);
+        mem.write_byte(0xff52, 0x00);
+        mem.write_byte(0xff53, 0x00);
+        mem.write_byte(0xff54, 0x00);
+        mem
+    }
+
+    #[test]
+    fn general_hdma_copies_every_block_at_once() {
+        let mut mem = hdma_memory();
+        mem.write_byte(0xff55, 0x01); // Two blocks
+        assert_eq!(mem.read_byte(0xff55), 0xff);
+        for i in 0..0x20 {
+            assert_eq!(mem.read_byte(0x8000 + i), 0x40 + i as u8);
+        }
+        assert_eq!(mem.read_byte(0x8020), 0);
+    }
+
+    #[test]
+    fn hblank_hdma_copies_a_block_per_hblank() {
+        let mut mem = hdma_memory();
+        mem.write_byte(0xff55, 0x81); // Two blocks, one per HBlank
+        assert_eq!(mem.read_byte(0xff55), 0x01);
+        assert_eq!(mem.read_byte(0x8000), 0);
+        for &(copied, left) in &[(0x10, 0x00), (0x20, 0xff)] {
+            while mem.gpu.mode() == 0 {
+                mem.gpu_cycle(4);
+            }
+            while mem.gpu.mode() != 0 {
+                mem.gpu_cycle(4);
+            }
+            assert_eq!(mem.read_byte(0x8000 + copied - 1), 0x40 + copied as u8 - 1);
+            assert_eq!(mem.read_byte(0x8000 + copied), 0);
+            assert_eq!(mem.read_byte(0xff55), left);
+        }
+    }
+
+    #[test]
+    fn lcdc_bit_0_blanks_the_bg_but_not_sprites() {
+        let mut mem = Memory::headless();
+        for address in 0x8000..0x9800 {
+            mem.write_byte(address, 0xff);
+        }
+        mem.write_byte(0xff47, 0xe4);
+        mem.write_byte(0xff48, 0xe4);
+        for (i, &b) in [16, 8, 0, 0].iter().enumerate() {
+            mem.write_byte(0xfe00 + i as u16, b);
+        }
+        // With the BG on, its tiles are solid black
+        for &(lcdc, sprite, bg) in &[(0x80, 255, 255), (0x82, 0, 255), (0x81, 0, 0)] {
+            mem.write_byte(0xff40, lcdc);
+            mem.gpu.scanline = 0;
+            mem.gpu.render_scanline();
+            let line = &mem.gpu.framebuffer()[..160];
+            assert!(line[..8].iter().all(|&level| level == sprite), "LCDC {:02x}", lcdc);
+            assert!(line[8..].iter().all(|&level| level == bg), "LCDC {:02x}", lcdc);
+        }
+    }
+
+    #[test]
+    fn last_tile_row_updates_the_tile_cache() {
+        let mut mem = Memory::headless();
+        mem.write_byte(0xff40, 0x81); // Signed tile numbers, 0x7f is the last tile
+        mem.write_byte(0xff47, 0xe4);
+        mem.write_byte(0xff42, 0x80);
+        mem.write_byte(0xff43, 0x80);
+        mem.write_byte(0x9800, 0x7f);
+        mem.write_byte(0x97fe, 0xaa);
+        mem.write_byte(0x97ff, 0x55);
+        let rgb = mem.gpu.render_map(TileMap::Background);
+        let row: Vec<u8> = (0..8).map(|x| rgb[(7 * 256 + x) * 3]).collect();
+        assert_eq!(row, vec![192, 96, 192, 96, 192, 96, 192, 96]);
+    }
+
+    #[test]
+    fn rom_writes_switch_banks_instead_of_storing() {
+        let mut rom = vec![0; 0x4000 * 0x80];
+        for bank in 0..0x80 {
+            rom[bank * 0x4000] = bank as u8;
+        }
+        rom[0x2000] = 0x99;
+        let mut mem = Memory::headless();
+        mem.load_cartridge(Cartridge::new(rom, MBC::MBC1, 0));
+        mem.write_byte(0x2000, 0x42);
+        assert_eq!(mem.read_byte(0x2000), 0x99);
+        assert_eq!(mem.read_byte(0x4000), 0x02); // MBC1 keeps the low 5 bits
+    }
+
+    #[test]
+    fn oam_dma_copies_160_bytes() {
+        let mut mem = Memory::headless();
+        for i in 0..0x100 {
+            mem.write_byte(0xc100 + i, i as u8 ^ 0x5a);
+        }
+        mem.write_byte(0xff46, 0xc1);
+        for i in 0..0xa0 {
+            assert_eq!(mem.read_byte(0xfe00 + i), i as u8 ^ 0x5a);
+        }
+        assert_eq!(mem.read_byte(0xfea0), 0);
+        assert_eq!(mem.take_dma_ticks(), 640);
+
+        // 0xe1 mirrors 0xc1
+        for i in 0..0xa0 {
+            mem.write_byte(0xfe00 + i, 0);
+        }
+        mem.write_byte(0xff46, 0xe1);
+        assert_eq!(mem.read_byte(0xfe9f), 0x9f ^ 0x5a);
+    }
+}
@@ -0,0 +1,56 @@
+use std::io;
+use std::io::prelude::*;
+use std::fs::File;
+
+const MOVIE_MAGIC: &[u8; 4] = b"RBMV";
+
+// Input movie for tool-assisted playback: a 4 byte magic, a little endian u32
+// seed and then one joypad bitmask (see Memory::set_joypad) per frame
+pub struct Movie {
+    pub seed: u32,
+    pub frames: Vec<u8>,
+}
+
+impl Movie {
+    pub fn new(seed: u32) -> Movie {
+        Movie {
+            seed,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, buttons: u8) {
+        self.frames.push(buttons);
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Option<Movie> {
+        if data.len() < 8 || data[0..4] != MOVIE_MAGIC[..] {
+            return None;
+        }
+        let seed = (data[4] as u32) | ((data[5] as u32) << 8) | ((data[6] as u32) << 16) | ((data[7] as u32) << 24);
+        Some(Movie {
+            seed,
+            frames: data[8..].to_vec(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = MOVIE_MAGIC.to_vec();
+        for i in 0..4 {
+            data.push((self.seed >> (i * 8)) as u8);
+        }
+        data.extend_from_slice(&self.frames);
+        data
+    }
+
+    pub fn load(filename: &str) -> io::Result<Movie> {
+        let mut data = vec![];
+        File::open(filename).and_then(|mut f| f.read_to_end(&mut data))?;
+        Movie::from_bytes(&data).ok_or(io::Error::new(io::ErrorKind::InvalidData, "not a movie file"))
+    }
+
+    pub fn save(&self, filename: &str) -> io::Result<()> {
+        let mut f = File::create(filename)?;
+        f.write_all(&self.to_bytes())
+    }
+}
@@ -0,0 +1,93 @@
+use cpu::Snapshot;
+
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+const LINE_HEIGHT: usize = GLYPH_HEIGHT + 1;
+const SCREEN_WIDTH: usize = 160;
+
+const TEXT_SHADE: u8 = 0;
+const BACK_SHADE: u8 = 255;
+
+// 3x5 fixed-width font, one row per byte with the leftmost pixel in bit 2
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        _ => [0; GLYPH_HEIGHT],
+    }
+}
+
+fn draw_text(buffer: &mut [u8], x: usize, y: usize, text: &str) {
+    for (i, c) in text.chars().enumerate() {
+        let rows = glyph(c);
+        let cx = x + i * (GLYPH_WIDTH + 1);
+        for row in 0..GLYPH_HEIGHT {
+            for col in 0..GLYPH_WIDTH {
+                if (rows[row] & (1 << (GLYPH_WIDTH - 1 - col))) != 0 {
+                    buffer[(y + row) * SCREEN_WIDTH + cx + col] = TEXT_SHADE;
+                }
+            }
+        }
+    }
+}
+
+// Draws the register/PPU HUD in the top left corner of a copy of the frame,
+// so the emulated pixel buffer itself is never touched
+pub fn draw(buffer: &mut [u8], snap: &Snapshot, fps: u32) {
+    let lines = [
+        format!("PC {:04X} SP {:04X}", snap.pc, snap.sp),
+        format!("AF {:02X}{:02X} BC {:02X}{:02X}", snap.a, snap.f, snap.b, snap.c),
+        format!("DE {:02X}{:02X} HL {:02X}{:02X}", snap.d, snap.e, snap.h, snap.l),
+        format!("LY {:02X} M{} FPS {}", snap.scanline, snap.gpu_mode, fps),
+    ];
+
+    let width = lines.iter().map(|l| l.len()).max().unwrap_or(0) * (GLYPH_WIDTH + 1) + 1;
+    let height = lines.len() * LINE_HEIGHT + 1;
+    for y in 0..height {
+        for x in 0..width {
+            buffer[y * SCREEN_WIDTH + x] = BACK_SHADE;
+        }
+    }
+
+    for (i, line) in lines.iter().enumerate() {
+        draw_text(buffer, 1, 1 + i * LINE_HEIGHT, line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::CPU;
+
+    #[test]
+    fn overlay_only_covers_the_top_left_corner() {
+        let snap = CPU::new_headless().snapshot();
+        let frame = [0x55; SCREEN_WIDTH * 144];
+        let mut buffer = frame;
+        draw(&mut buffer, &snap, 60);
+        let top = &buffer[..LINE_HEIGHT * SCREEN_WIDTH];
+        assert!(top.iter().zip(frame.iter()).any(|(a, b)| a != b));
+        assert!(top.contains(&TEXT_SHADE) && top.contains(&BACK_SHADE));
+        assert_eq!(&buffer[SCREEN_WIDTH * 100..], &frame[SCREEN_WIDTH * 100..]);
+    }
+}
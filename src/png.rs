@@ -0,0 +1,85 @@
+// Minimal PNG writer for debug images, https://www.w3.org/TR/PNG/
+// The image data goes into stored DEFLATE blocks, so no compressor is needed.
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+const SIGNATURE: [u8; 8] = [0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a];
+const MAX_STORED: usize = 0xffff; // Largest stored block
+
+fn crc32(chunks: &[&[u8]]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for chunk in chunks {
+        for &byte in chunk.iter() {
+            crc ^= byte as u32;
+            for _ in 0..8 {
+                crc = if (crc & 1) != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+            }
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn be32(value: u32) -> [u8; 4] {
+    [(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]
+}
+
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8], data: &[u8]) -> io::Result<()> {
+    out.write_all(&be32(data.len() as u32))?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    out.write_all(&be32(crc32(&[kind, data])))
+}
+
+// zlib stream made of uncompressed blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_STORED).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let len = block.len() as u16;
+        out.push(if blocks.peek().is_none() { 1 } else { 0 });
+        out.extend_from_slice(&[len as u8, (len >> 8) as u8, !len as u8, (!len >> 8) as u8]);
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&be32(adler32(data)));
+    out
+}
+
+// Encodes 8 bit RGB pixels, `rgb` holds width * height * 3 bytes
+pub fn encode_rgb(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    let stride = width as usize * 3;
+    let mut raw = Vec::with_capacity((stride + 1) * height as usize);
+    for row in rgb.chunks(stride).take(height as usize) {
+        raw.push(0); // Filter type None
+        raw.extend_from_slice(row);
+    }
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&be32(width));
+    header.extend_from_slice(&be32(height));
+    header.extend_from_slice(&[8, 2, 0, 0, 0]); // 8 bit depth, truecolor
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header).unwrap();
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw)).unwrap();
+    write_chunk(&mut png, b"IEND", &[]).unwrap();
+    png
+}
+
+pub fn write_rgb<P: AsRef<Path>>(path: P, width: u32, height: u32, rgb: &[u8]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(&encode_rgb(width, height, rgb))
+}
@@ -1,5 +1,3 @@
-use std::io;
-
 // https://realboyemulator.files.wordpress.com/2013/01/gbcpuman.pdf Page 62
 #[derive(Copy, Clone)]
 pub enum Flags {
@@ -9,6 +7,7 @@ pub enum Flags {
     C = 0b00010000, // Flag CARRY
 }
 
+#[allow(non_snake_case)]
 pub struct Registers {
     pub A: u8,
     pub F: u8,
@@ -22,6 +21,12 @@ pub struct Registers {
     pub PC: u16,
 }
 
+impl Default for Registers {
+    fn default() -> Registers {
+        Registers::new()
+    }
+}
+
 // https://realboyemulator.files.wordpress.com/2013/01/gbcpuman.pdf Page 17
 impl Registers {
     pub fn new() -> Registers {
@@ -88,14 +93,26 @@ impl Registers {
         self.L = (operand & 0x00ff) as u8;
     }
 
+    // The low nibble of F is not wired and always reads back as 0
     pub fn flag_set(&mut self, flag: Flags) {
         let mask = flag as u8;
-        self.F |= mask;
+        self.F = (self.F | mask) & 0xf0;
     }
 
     pub fn flag_reset(&mut self, flag: Flags) {
         let mask = flag as u8;
-        self.F &= !mask;
+        self.F &= !mask & 0xf0;
+    }
+
+    // Updates all four flags at once, None leaves that flag unchanged
+    pub fn set_flags_znhc(&mut self, z: Option<bool>, n: Option<bool>, h: Option<bool>, c: Option<bool>) {
+        for &(flag, value) in &[(Flags::Z, z), (Flags::N, n), (Flags::H, h), (Flags::C, c)] {
+            match value {
+                Some(true) => self.flag_set(flag),
+                Some(false) => self.flag_reset(flag),
+                None => {}
+            }
+        }
     }
 
     pub fn flag_get(&self, flag: Flags) -> bool {
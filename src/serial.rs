@@ -0,0 +1,124 @@
+use state::{StateReader, StateWriter};
+
+use std::io;
+use std::io::prelude::*;
+
+// http://gbdev.gg8.se/wiki/articles/Serial_Data_Transfer_(Link_Cable)
+// Without a link partner every transfer driven by the internal clock shifts in
+// 0xff. Clearing the start bit of SC before the 8 bits are out cancels it.
+// Every byte sent is also handed to an output callback, test ROMs such as
+// Blargg's report their results this way. By default it's printed to stdout.
+const TRANSFER_CYCLES: u32 = 8 * 512; // 8 bits at 8192 Hz
+pub const STATE_SIZE: usize = 6; // Bytes written by save_state
+
+pub struct Serial {
+    data: u8,       // SB
+    control: u8,    // SC, bit 7 transfer start, bit 0 internal clock
+    remaining: u32, // Cycles until the running transfer completes
+    output: Box<dyn FnMut(u8)>,
+}
+
+fn print_byte(value: u8) {
+    print!("{}", value as char);
+    let _ = io::stdout().flush();
+}
+
+impl Default for Serial {
+    fn default() -> Serial {
+        Serial::new()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            data: 0,
+            control: 0,
+            remaining: 0,
+            output: Box::new(print_byte),
+        }
+    }
+
+    pub fn set_output(&mut self, output: Box<dyn FnMut(u8)>) {
+        self.output = output;
+    }
+
+    // Keeps the output callback
+    pub fn reset(&mut self) {
+        self.data = 0;
+        self.control = 0;
+        self.remaining = 0;
+    }
+
+    // Returns true when a transfer completed and the serial interrupt should fire
+    pub fn step(&mut self, cycles: u32) -> bool {
+        if self.remaining == 0 { return false; }
+        if cycles < self.remaining {
+            self.remaining -= cycles;
+            return false;
+        }
+        self.remaining = 0;
+        self.data = 0xff;
+        self.control &= 0x7f;
+        true
+    }
+
+    pub fn read_byte(&self, address: u16) -> u8 {
+        match address {
+            0xff01 => { self.data }
+            0xff02 => { 0x7e | self.control }
+            _ => 0xff
+        }
+    }
+
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        match address {
+            0xff01 => { self.data = value; }
+            0xff02 => { self.control = value & 0x81;
+                        // Only the internal clock runs without a partner
+                        self.remaining = if (value & 0x81) == 0x81 { TRANSFER_CYCLES } else { 0 };
+                        if self.remaining != 0 { (self.output)(self.data); }
+                      }
+            _ => {}
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.data);
+        w.u8(self.control);
+        w.u32(self.remaining);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) {
+        self.data = r.u8();
+        self.control = r.u8();
+        self.remaining = r.u32();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transfer_completes_after_8_bits() {
+        let mut serial = Serial::new();
+        serial.set_output(Box::new(|_| {}));
+        serial.write_byte(0xff01, 0x42);
+        serial.write_byte(0xff02, 0x81);
+        assert!(!serial.step(TRANSFER_CYCLES - 4));
+        assert!(serial.step(4));
+        assert_eq!(serial.read_byte(0xff01), 0xff);
+        assert_eq!(serial.read_byte(0xff02), 0x7f);
+    }
+
+    #[test]
+    fn clearing_the_start_bit_cancels_the_transfer() {
+        let mut serial = Serial::new();
+        serial.set_output(Box::new(|_| {}));
+        serial.write_byte(0xff02, 0x81);
+        assert!(!serial.step(TRANSFER_CYCLES / 2));
+        serial.write_byte(0xff02, 0x01);
+        assert!(!serial.step(TRANSFER_CYCLES));
+    }
+}
@@ -0,0 +1,107 @@
+// Save state encoding. Every component writes its fields in a fixed order after
+// a short header, all values little endian. Bump STATE_VERSION whenever the
+// layout of any component changes.
+const STATE_MAGIC: &[u8; 4] = b"RBST";
+const STATE_VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 5; // Magic and version
+
+#[derive(Debug)]
+pub enum StateError {
+    BadHeader,  // Not a save state or from another version
+    BadSize,    // Truncated, or saved with a different cartridge
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl Default for StateWriter {
+    fn default() -> StateWriter {
+        StateWriter::new()
+    }
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        let mut data = STATE_MAGIC.to_vec();
+        data.push(STATE_VERSION);
+        StateWriter {
+            data,
+        }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&[value as u8, (value >> 8) as u8]);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        for i in 0..4 {
+            self.data.push((value >> (i * 8)) as u8);
+        }
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        for i in 0..8 {
+            self.data.push((value >> (i * 8)) as u8);
+        }
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.data.extend_from_slice(value);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<StateReader<'a>, StateError> {
+        if data.len() < HEADER_SIZE || data[0..4] != STATE_MAGIC[..] || data[4] != STATE_VERSION {
+            return Err(StateError::BadHeader);
+        }
+        Ok(StateReader { data, pos: HEADER_SIZE })
+    }
+
+    // Reads past the end yield zeros, CPU::load_state checks the size up front
+    pub fn u8(&mut self) -> u8 {
+        let value = self.data.get(self.pos).map_or(0, |v| *v);
+        self.pos += 1;
+        value
+    }
+
+    pub fn bool(&mut self) -> bool {
+        self.u8() != 0
+    }
+
+    pub fn u16(&mut self) -> u16 {
+        self.u8() as u16 | (self.u8() as u16) << 8
+    }
+
+    pub fn u32(&mut self) -> u32 {
+        (0..4).fold(0, |acc, i| acc | (self.u8() as u32) << (i * 8))
+    }
+
+    pub fn u64(&mut self) -> u64 {
+        (0..8).fold(0, |acc, i| acc | (self.u8() as u64) << (i * 8))
+    }
+
+    pub fn bytes(&mut self, out: &mut [u8]) {
+        for b in out.iter_mut() {
+            *b = self.u8();
+        }
+    }
+}
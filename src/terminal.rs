@@ -0,0 +1,90 @@
+use gpu::{FrameSink, SCREEN_WIDTH, SCREEN_HEIGHT};
+
+use std::io;
+use std::io::prelude::*;
+
+// Every character covers a 2x4 pixel cell, 80x36 characters for the whole screen
+const CELL_WIDTH: usize = 2;
+const CELL_HEIGHT: usize = 4;
+
+// Lightest shade first
+const SHADE_CHARS: [char; 4] = [' ', '.', '+', '#'];
+
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+// Draws frames as ASCII art on the terminal, no SDL needed
+pub struct TerminalDisplay {
+    clear: bool,    // Clear the terminal before every frame
+}
+
+impl Default for TerminalDisplay {
+    fn default() -> TerminalDisplay {
+        TerminalDisplay::new()
+    }
+}
+
+impl TerminalDisplay {
+    pub fn new() -> TerminalDisplay {
+        TerminalDisplay {
+            clear: true,
+        }
+    }
+
+    // Renders a 160x144 frame of gray levels, each cell shows its average shade.
+    // Levels in between the four shades (e.g. from frame blending) count as the nearest
+    pub fn render(&self, frame: &[u8]) -> String {
+        let width = SCREEN_WIDTH as usize;
+        let height = SCREEN_HEIGHT as usize;
+        let indices: Vec<u8> = frame.iter().map(|&level| 3 - ((level as u16 * 3 + 127) / 255) as u8).collect();
+
+        let mut text = String::with_capacity((width / CELL_WIDTH + 1) * height / CELL_HEIGHT);
+        for cy in 0..height / CELL_HEIGHT {
+            for cx in 0..width / CELL_WIDTH {
+                let mut sum = 0;
+                for y in 0..CELL_HEIGHT {
+                    for x in 0..CELL_WIDTH {
+                        sum += indices[(cy * CELL_HEIGHT + y) * width + cx * CELL_WIDTH + x] as usize;
+                    }
+                }
+                let cell = CELL_WIDTH * CELL_HEIGHT;
+                text.push(SHADE_CHARS[(sum + cell / 2) / cell]);
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    pub fn set_clear(&mut self, clear: bool) {
+        self.clear = clear;
+    }
+
+    pub fn present(&mut self, frame: &[u8]) -> io::Result<()> {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        if self.clear {
+            out.write_all(CLEAR_SCREEN.as_bytes())?;
+        }
+        out.write_all(self.render(frame).as_bytes())?;
+        out.flush()
+    }
+}
+
+// Frames arrive as gray RGB, any channel is the shade
+impl FrameSink for TerminalDisplay {
+    fn present(&mut self, frame: &[u8; 160 * 144 * 3]) {
+        let shades: Vec<u8> = frame.chunks(3).map(|p| p[0]).collect();
+        let _ = TerminalDisplay::present(self, &shades);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_four_shade_bands() {
+        let frame: Vec<u8> = (0..160 * 144).map(|i| [255, 192, 96, 0][(i % 160) / 40]).collect();
+        let line = format!("{}{}{}{}\n", " ".repeat(20), ".".repeat(20), "+".repeat(20), "#".repeat(20));
+        assert_eq!(TerminalDisplay::new().render(&frame), line.repeat(36));
+    }
+}
@@ -0,0 +1,156 @@
+use state::{StateReader, StateWriter};
+
+pub const STATE_SIZE: usize = 6; // Bytes written by save_state
+
+// http://gbdev.gg8.se/wiki/articles/Timer_Obscure_Behaviour
+// DIV is the upper byte of a 16-bit counter running at the CPU clock. TIMA
+// increments on a falling edge of the counter bit selected by TAC, which is
+// why writing DIV or TAC can also tick TIMA.
+// On overflow TIMA reads 0 for 4 cycles before it is reloaded from TMA and the
+// interrupt is requested. Writing TIMA during those cycles cancels the reload.
+pub struct Timer {
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    reloading: bool,    // TIMA overflowed during the last 4 cycles
+}
+
+impl Default for Timer {
+    fn default() -> Timer {
+        Timer::new()
+    }
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            counter: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            reloading: false,
+        }
+    }
+
+    // Counter bit feeding TIMA for each clock select (4096/262144/65536/16384 Hz)
+    fn input_bit(&self) -> bool {
+        let bit = match self.tac & 0x03 {
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            _ => 7,
+        };
+        (self.tac & 0x04) != 0 && ((self.counter >> bit) & 0x01) != 0
+    }
+
+    fn increment_tima(&mut self) {
+        if self.tima == 0xff {
+            self.tima = 0;
+            self.reloading = true;
+        } else {
+            self.tima += 1;
+        }
+    }
+
+    // Returns true when TIMA overflowed and the timer interrupt should fire
+    pub fn step(&mut self, cycles: u32) -> bool {
+        let mut overflow = false;
+        for _ in 0..(cycles / 4) {
+            if self.reloading {
+                self.reloading = false;
+                self.tima = self.tma;
+                overflow = true;
+            }
+            let before = self.input_bit();
+            self.counter = self.counter.wrapping_add(4);
+            if before && !self.input_bit() {
+                self.increment_tima();
+            }
+        }
+        overflow
+    }
+
+    pub fn read_byte(&self, address: u16) -> u8 {
+        match address {
+            0xff04 => { (self.counter >> 8) as u8 }
+            0xff05 => { self.tima }
+            0xff06 => { self.tma }
+            0xff07 => { 0xf8 | self.tac }
+            _ => 0xff
+        }
+    }
+
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        let before = self.input_bit();
+        match address {
+            0xff04 => { self.counter = 0; }
+            0xff05 => { self.tima = value; self.reloading = false; }
+            0xff06 => { self.tma = value; }
+            0xff07 => { self.tac = value & 0x07; }
+            _ => {}
+        }
+        if before && !self.input_bit() {
+            self.increment_tima();
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.counter);
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.u8(self.tac);
+        w.bool(self.reloading);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) {
+        self.counter = r.u16();
+        self.tima = r.u8();
+        self.tma = r.u8();
+        self.tac = r.u8();
+        self.reloading = r.bool();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tima_counts_at_each_clock_select() {
+        for &(tac, period) in [(0x04, 1024), (0x05, 16), (0x06, 64), (0x07, 256)].iter() {
+            let mut timer = Timer::new();
+            timer.write_byte(0xff07, tac);
+            assert!(!timer.step(period * 10));
+            assert_eq!(timer.read_byte(0xff05), 10, "TAC {:02X}", tac);
+            timer.step(period - 4);
+            assert_eq!(timer.read_byte(0xff05), 10, "TAC {:02X}", tac);
+        }
+    }
+
+    #[test]
+    fn overflow_reloads_from_tma() {
+        let mut timer = Timer::new();
+        timer.write_byte(0xff06, 0xab);
+        timer.write_byte(0xff05, 0xff);
+        timer.write_byte(0xff07, 0x05);
+        assert!(!timer.step(16));
+        assert_eq!(timer.read_byte(0xff05), 0x00);
+        assert!(timer.step(4));
+        assert_eq!(timer.read_byte(0xff05), 0xab);
+    }
+
+    #[test]
+    fn div_write_ticks_tima_on_a_falling_edge() {
+        let mut timer = Timer::new();
+        timer.write_byte(0xff07, 0x05);
+        timer.step(8); // Bit 3 of the counter is now set
+        timer.write_byte(0xff04, 0);
+        assert_eq!(timer.read_byte(0xff05), 1);
+        assert_eq!(timer.read_byte(0xff04), 0);
+
+        timer.step(4); // Bit 3 clear, resetting DIV has no edge
+        timer.write_byte(0xff04, 0);
+        assert_eq!(timer.read_byte(0xff05), 1);
+    }
+}